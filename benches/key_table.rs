@@ -0,0 +1,53 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 比较逐字节异或与按字宽异或的吞吐量
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use denshaded_tools::kcap::{apply_key_table, create_key_table, KeyTable};
+
+/// 原先 `read_to` / `write_to` 中使用的逐字节实现
+fn apply_key_table_per_byte(key_table: &KeyTable, data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .enumerate()
+        .map(|(i, &x)| x ^ key_table[i % key_table.len()])
+        .collect()
+}
+
+fn bench_key_table(c: &mut Criterion) {
+    let key_table = create_key_table("PackPass");
+    let mut group = c.benchmark_group("key_table");
+    for &size in &[0x1000usize, 0x100000, 0x1000000] {
+        let data: Vec<u8> = (0..size).map(|i| i as u8).collect();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("per_byte", size), &data, |b, data| {
+            b.iter(|| apply_key_table_per_byte(&key_table, black_box(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("word_wide", size), &data, |b, data| {
+            b.iter(|| {
+                let mut data = data.clone();
+                apply_key_table(&key_table, black_box(&mut data));
+                data
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_key_table);
+criterion_main!(benches);
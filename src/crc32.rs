@@ -23,16 +23,16 @@ use lazy_static::lazy_static;
 lazy_static! {
     static ref CRC_TABLE: [u32; 256] = {
         let mut table = [0u32; 256];
-        for n in 0..256 {
+        for (n, entry) in table.iter_mut().enumerate() {
             let mut c = n;
             for _ in 0..8 {
                 if 0 != (c & 1) {
                     c = 0xedb88320 ^ (c >> 1);
                 } else {
-                    c = c >> 1;
+                    c >>= 1;
                 }
             }
-            table[n] = c as u32;
+            *entry = c as u32;
         }
         table
     };
@@ -41,7 +41,7 @@ lazy_static! {
 pub fn update_crc(crc: u32, buf: &[u8], pos: usize, len: usize) -> u32 {
     let mut c = crc;
     for n in 0..len {
-        c = CRC_TABLE[(((c ^ (buf[pos + n] as u32)) as usize) & 0xFF) as usize] ^ (c >> 8);
+        c = CRC_TABLE[((c ^ (buf[pos + n] as u32)) as usize) & 0xFF] ^ (c >> 8);
     }
     c
 }
@@ -20,112 +20,567 @@
 
 use anyhow::{Error, Result};
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
-use encoding_rs::SHIFT_JIS;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io::{Read, Write};
+use std::str::FromStr;
+use std::sync::Arc;
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-struct FVT {
-    tag: String,
-    u32_unknown0: u32,
-    u32_unknown1: u32,
-    u32_unknown2: u32,
-    u32_unknown3: u32,
-    u8_unknown0: u8,
-    u8_unknown1: u8,
-    text: String,
+use crate::text::{charmap::CharMap, TextEncoding};
+
+pub mod analyze;
+pub mod batch;
+pub mod catalog;
+pub mod convert;
+pub mod format;
+pub mod schema;
+pub mod subtitle;
+pub mod variant;
+
+pub use format::DataFormat;
+pub use variant::{detect, Detection, FvtVariant};
+
+const DEND_FVT: &[u8] = b"DEND_FVT"; // E: Lighting Stage
+const D2_FVT: &[u8] = b"D2_FVT"; // 2: Burning Stage
+const D3_FVT: &[u8] = b"D3_FVT"; // 3: Climax Stage & Rising Stage
+
+/// 一条记录的文本长度以一个字节保存
+pub const MAX_TEXT_LENGTH: usize = 255;
+
+/// 编码后的文本超出 `MAX_TEXT_LENGTH` 时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OverflowPolicy {
+    /// 报错，不写出文件
+    #[default]
+    Error,
+    /// 在字符边界拆分为多条字段相同的记录
+    Split,
+    /// 在字符边界截断
+    Truncate,
 }
 
-pub fn decode(input: &mut impl Read, output: &mut impl Write) -> Result<()> {
-    let mut fvt = FVT::default();
-    let mut tag = [0; 8];
-    input.read_exact(&mut tag[0..1])?;
-    input.read_exact(&mut tag[0..1])?;
-    match tag[0] {
-        0x45 => {
-            fvt.tag = "DEND_FVT".into();
-            // E: Lighting Stage
-            input.read_exact(&mut tag[0..6])?;
-            fvt.u32_unknown0 = input.read_u32::<LE>()?;
-            fvt.u8_unknown0 = input.read_u8()?;
-            let text_length = input.read_u8()?;
-            fvt.u8_unknown1 = input.read_u8()?;
-            let mut text = vec![0; text_length as usize];
-            input.read_exact(&mut text)?;
-            let (text, _, _error) = SHIFT_JIS.decode(&text);
-            fvt.text = text.to_string();
-            serde_json::to_writer_pretty(output, &fvt)?;
-            Ok(())
-        }
-        0x32 => {
-            fvt.tag = "D2_FVT".into();
-            // 2: Burning Stage
-            input.read_exact(&mut tag[0..4])?;
-            fvt.u32_unknown0 = input.read_u32::<LE>()?;
-            fvt.u32_unknown1 = input.read_u32::<LE>()?;
-            fvt.u32_unknown2 = input.read_u32::<LE>()?;
-            fvt.u8_unknown0 = input.read_u8()?;
-            let text_length = input.read_u8()?;
-            fvt.u8_unknown1 = input.read_u8()?;
-            let mut text = vec![0; text_length as usize];
-            input.read_exact(&mut text)?;
-            let (text, _, _error) = SHIFT_JIS.decode(&text);
-            fvt.text = text.to_string();
-            serde_json::to_writer_pretty(output, &fvt)?;
-            Ok(())
+impl FromStr for OverflowPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "error" => Ok(Self::Error),
+            "split" => Ok(Self::Split),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err(Error::msg(format!("Unknown overflow policy: {}", s))),
         }
-        0x33 => {
-            fvt.tag = "D3_FVT".into();
-            // 3: Climax Stage & Rising Stage
-            input.read_exact(&mut tag[0..4])?;
-            fvt.u32_unknown0 = input.read_u32::<LE>()?;
-            fvt.u32_unknown1 = input.read_u32::<LE>()?;
-            fvt.u32_unknown2 = input.read_u32::<LE>()?;
-            fvt.u8_unknown0 = input.read_u8()?;
-            let text_length = input.read_u8()?;
-            fvt.u8_unknown1 = input.read_u8()?;
-            let mut text = vec![0; text_length as usize];
-            input.read_exact(&mut text)?;
-            let (text, _, _error) = SHIFT_JIS.decode(&text);
-            fvt.text = text.to_string();
-            serde_json::to_writer_pretty(output, &fvt)?;
-            Ok(())
+    }
+}
+
+/// 解码字幕时的选项
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub encoding: TextEncoding,
+    /// 输出的文本表示
+    pub format: DataFormat,
+    /// 解码后应用的字符映射表
+    pub charmap: Option<Arc<CharMap>>,
+}
+
+/// 编码字幕时的选项
+#[derive(Debug, Clone, Default)]
+pub struct EncodeOptions {
+    /// 输入的文本表示
+    pub format: DataFormat,
+    pub overflow: OverflowPolicy,
+    /// 遇到无法编码的字符时报错而不是警告
+    pub strict: bool,
+    /// 覆盖 json 中记录的文本编码
+    pub encoding: Option<TextEncoding>,
+    /// 编码前应用的字符映射表
+    pub charmap: Option<Arc<CharMap>>,
+}
+
+/// 数据是否以已知的 FVT 标识开头
+pub fn has_magic(data: &[u8]) -> bool {
+    matches!(detect(data), Detection::Known(_))
+}
+
+/// 一个字幕文件，标识之后是若干条连续的记录
+///
+/// 反序列化时也接受旧版本导出的只有一条记录的平铺格式。
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+#[serde(from = "FvtData")]
+pub struct Fvt {
+    pub tag: String,
+    /// 文本编码，默认为 Shift-JIS
+    #[serde(default, skip_serializing_if = "TextEncoding::is_default")]
+    pub encoding: TextEncoding,
+    pub records: Vec<FvtRecord>,
+}
+
+/// `Fvt` 反序列化时接受的格式
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FvtData {
+    Records {
+        tag: String,
+        #[serde(default)]
+        encoding: TextEncoding,
+        records: Vec<FvtRecord>,
+    },
+    /// 旧版本导出的格式，tag 与唯一一条记录的字段平铺在一起
+    Legacy {
+        tag: String,
+        #[serde(flatten)]
+        record: FvtRecord,
+    },
+}
+
+impl From<FvtData> for Fvt {
+    fn from(data: FvtData) -> Self {
+        match data {
+            FvtData::Records {
+                tag,
+                encoding,
+                records,
+            } => Self {
+                tag,
+                encoding,
+                records,
+            },
+            FvtData::Legacy { tag, record } => Self {
+                tag,
+                encoding: TextEncoding::default(),
+                records: vec![record],
+            },
         }
-        _ => Err(Error::msg("Unknown fvt type")),
     }
 }
 
-pub fn encode(input: &mut impl Read, output: &mut impl Write) -> Result<()> {
-    let fvt: FVT = serde_json::from_reader(input)?;
-    match fvt.tag.as_str() {
-        "DEND_FVT" => {
-            output.write(&fvt.tag.as_bytes())?;
-            output.write_u32::<LE>(fvt.u32_unknown0)?;
-            output.write_u8(fvt.u8_unknown0)?;
-            let (text, _, _error) = SHIFT_JIS.encode(&fvt.text);
-            if _error {
-                println!("WARN: Text has occured encoding error from UTF-8 to SHIFT-JIS");
+/// 一条字幕记录，Lighting Stage 的记录只有 `u32_unknown0`
+#[derive(Debug, Clone, Serialize, Deserialize, Default, PartialEq)]
+pub struct FvtRecord {
+    pub u32_unknown0: u32,
+    pub u32_unknown1: u32,
+    pub u32_unknown2: u32,
+    pub u8_unknown0: u8,
+    pub u8_unknown1: u8,
+    /// 无法原样还原的字节以 `\xHH` 转义，见 `crate::text`
+    pub text: String,
+}
+
+impl Fvt {
+    pub fn from_read(input: &mut impl Read) -> Result<Self> {
+        Self::from_read_with(input, TextEncoding::default())
+    }
+
+    pub fn from_read_with(input: &mut impl Read, encoding: TextEncoding) -> Result<Self> {
+        let mut head = [0; 2];
+        input.read_exact(&mut head)?;
+        let magic = FvtVariant::from_type_byte(head[1])
+            .ok_or_else(|| Error::msg("Unknown fvt type"))?
+            .magic();
+        let mut tag = vec![0; magic.len()];
+        tag[..2].copy_from_slice(&head);
+        input.read_exact(&mut tag[2..])?;
+        if tag != magic {
+            return Err(Error::msg(format!(
+                "Broken fvt magic {:?}, expected {:?}",
+                String::from_utf8_lossy(&tag),
+                String::from_utf8_lossy(magic)
+            )));
+        }
+
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let mut rest = &data[..];
+        let mut records = Vec::new();
+        while !rest.is_empty() {
+            let offset = magic.len() + data.len() - rest.len();
+            let remain = rest.len();
+            let record = FvtRecord::from_read(&mut rest, magic, encoding).map_err(|_| {
+                Error::msg(format!(
+                    "{} trailing bytes at offset {} can't be parsed as a fvt record",
+                    remain, offset
+                ))
+            })?;
+            records.push(record);
+        }
+        Ok(Self {
+            tag: String::from_utf8_lossy(magic).into(),
+            encoding,
+            records,
+        })
+    }
+
+    pub fn write_to(&self, output: &mut impl Write) -> Result<()> {
+        self.write_with(output, false)
+    }
+
+    /// `strict` 时遇到无法编码的字符报错
+    pub fn write_with(&self, output: &mut impl Write, strict: bool) -> Result<()> {
+        let magic = FvtVariant::from_tag(&self.tag)
+            .ok_or_else(|| Error::msg("Unknown fvt type"))?
+            .magic();
+        output.write_all(magic)?;
+        for (i, record) in self.records.iter().enumerate() {
+            let text = self
+                .encoding
+                .encode_checked(&record.text, strict)
+                .map_err(|error| Error::msg(format!("Record {}: {}", i, error)))?;
+            if text.len() > MAX_TEXT_LENGTH {
+                return Err(Error::msg(format!(
+                    "Text of record {} is {} bytes in {}, longer than the limit of {} bytes",
+                    i,
+                    text.len(),
+                    self.encoding,
+                    MAX_TEXT_LENGTH
+                )));
+            }
+            record.write_to(output, magic, &text)?;
+        }
+        Ok(())
+    }
+
+    /// 应用编码选项中的文本编码、字符映射表与超长处理方式
+    pub fn prepare(&mut self, options: &EncodeOptions) {
+        if let Some(encoding) = options.encoding {
+            self.encoding = encoding;
+        }
+        if let Some(charmap) = &options.charmap {
+            for record in &mut self.records {
+                record.text = charmap.apply_encoding(self.encoding, &record.text);
+            }
+        }
+        self.fit_text(options.overflow);
+    }
+
+    /// 读取 `options.format` 格式的文本表示并按编码选项校验，一次报告所有问题
+    pub fn from_data(input: &mut impl Read, options: &EncodeOptions) -> Result<Self> {
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let value = options.format.to_value(&data)?;
+        let problems = schema::validate(&value, options);
+        if !problems.is_empty() {
+            let mut message = format!("{} problems found", problems.len());
+            for problem in problems {
+                message.push_str(&format!("\n    {}", problem));
             }
-            output.write_u8(text.len() as u8)?;
-            output.write_u8(fvt.u8_unknown1)?;
-            output.write_all(&text)?;
-            Ok(())
+            return Err(Error::msg(message));
         }
-        "D2_FVT" | "D3_FVT" => {
-            output.write(&fvt.tag.as_bytes())?;
-            output.write_u32::<LE>(fvt.u32_unknown0)?;
-            output.write_u32::<LE>(fvt.u32_unknown1)?;
-            output.write_u32::<LE>(fvt.u32_unknown2)?;
-            output.write_u8(fvt.u8_unknown0)?;
-            let (text, _encoding, _error) = SHIFT_JIS.encode(&fvt.text);
-            if _error {
-                println!("WARN: Text has occured encoding error from UTF-8 to SHIFT-JIS");
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// 解码后应用字符映射表
+    pub fn map_decoded(&mut self, charmap: &CharMap) {
+        for record in &mut self.records {
+            record.text = charmap.apply_decoded(self.encoding, &record.text);
+        }
+    }
+
+    /// 无法以当前编码编码的字符及其出现次数
+    pub fn unencodable(&self) -> BTreeMap<char, usize> {
+        let mut chars = BTreeMap::new();
+        for record in &self.records {
+            for c in self.encoding.encode(&record.text).1 {
+                *chars.entry(c).or_default() += 1;
+            }
+        }
+        chars
+    }
+
+    /// 按策略处理超长的文本，`OverflowPolicy::Error` 时不做修改，由 `write_to` 报错
+    pub fn fit_text(&mut self, policy: OverflowPolicy) {
+        let encoding = self.encoding;
+        match policy {
+            OverflowPolicy::Error => {}
+            OverflowPolicy::Truncate => {
+                for record in &mut self.records {
+                    if let Some(text) = split_text(record, encoding).into_iter().next() {
+                        record.text = text;
+                    }
+                }
+            }
+            OverflowPolicy::Split => {
+                self.records = self
+                    .records
+                    .iter()
+                    .flat_map(|record| {
+                        split_text(record, encoding)
+                            .into_iter()
+                            .map(move |text| FvtRecord {
+                                text,
+                                ..record.clone()
+                            })
+                    })
+                    .collect();
             }
-            output.write_u8(text.len() as u8)?;
-            output.write_u8(fvt.u8_unknown1)?;
-            output.write_all(&text)?;
-            Ok(())
         }
-        _ => Err(Error::msg("Unknown fvt type")),
     }
 }
+
+/// 在字符边界把文本拆成编码后不超过 `MAX_TEXT_LENGTH` 的若干段，未超长时原样返回
+fn split_text(record: &FvtRecord, encoding: TextEncoding) -> Vec<String> {
+    if encoding.encode(&record.text).0.len() <= MAX_TEXT_LENGTH {
+        return vec![record.text.clone()];
+    }
+    encoding.split(&record.text, MAX_TEXT_LENGTH)
+}
+
+impl FvtRecord {
+    fn from_read(input: &mut impl Read, magic: &[u8], encoding: TextEncoding) -> Result<Self> {
+        let mut record = FvtRecord {
+            u32_unknown0: input.read_u32::<LE>()?,
+            ..Default::default()
+        };
+        if magic != DEND_FVT {
+            record.u32_unknown1 = input.read_u32::<LE>()?;
+            record.u32_unknown2 = input.read_u32::<LE>()?;
+        }
+        record.u8_unknown0 = input.read_u8()?;
+        let text_length = input.read_u8()?;
+        record.u8_unknown1 = input.read_u8()?;
+        let mut text = vec![0; text_length as usize];
+        input.read_exact(&mut text)?;
+        record.text = encoding.decode(&text);
+        Ok(record)
+    }
+
+    fn write_to(&self, output: &mut impl Write, magic: &[u8], text: &[u8]) -> Result<()> {
+        output.write_u32::<LE>(self.u32_unknown0)?;
+        if magic != DEND_FVT {
+            output.write_u32::<LE>(self.u32_unknown1)?;
+            output.write_u32::<LE>(self.u32_unknown2)?;
+        }
+        output.write_u8(self.u8_unknown0)?;
+        output.write_u8(text.len() as u8)?;
+        output.write_u8(self.u8_unknown1)?;
+        output.write_all(text)?;
+        Ok(())
+    }
+}
+
+pub fn decode(input: &mut impl Read, output: &mut impl Write) -> Result<()> {
+    decode_with(input, output, &DecodeOptions::default())
+}
+
+pub fn decode_with(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    options: &DecodeOptions,
+) -> Result<()> {
+    let mut fvt = Fvt::from_read_with(input, options.encoding)?;
+    if let Some(charmap) = &options.charmap {
+        fvt.map_decoded(charmap);
+    }
+    output.write_all(&options.format.to_vec(&fvt)?)?;
+    Ok(())
+}
+
+pub fn encode(input: &mut impl Read, output: &mut impl Write) -> Result<()> {
+    encode_with(input, output, &EncodeOptions::default())
+}
+
+pub fn encode_with(
+    input: &mut impl Read,
+    output: &mut impl Write,
+    options: &EncodeOptions,
+) -> Result<()> {
+    let mut fvt = Fvt::from_data(input, options)?;
+    fvt.prepare(options);
+    fvt.write_with(output, options.strict)
+}
+
+#[cfg(test)]
+fn sample_record(magic: &[u8], text: &[u8]) -> Vec<u8> {
+    let mut data = 0x01020304u32.to_le_bytes().to_vec();
+    if magic != DEND_FVT {
+        data.extend_from_slice(&0x0A0B0C0Du32.to_le_bytes());
+        data.extend_from_slice(&0xFFFFFFFFu32.to_le_bytes());
+    }
+    data.push(7);
+    data.push(text.len() as u8);
+    data.push(200);
+    data.extend_from_slice(text);
+    data
+}
+
+#[cfg(test)]
+pub(crate) fn sample(magic: &[u8], texts: &[&[u8]]) -> Vec<u8> {
+    let mut data = magic.to_vec();
+    for text in texts {
+        data.extend(sample_record(magic, text));
+    }
+    data
+}
+
+#[cfg(test)]
+fn round_trip(data: &[u8]) -> Vec<u8> {
+    let mut json = Vec::new();
+    decode(&mut &data[..], &mut json).unwrap();
+    let mut encoded = Vec::new();
+    encode(&mut &json[..], &mut encoded).unwrap();
+    encoded
+}
+
+#[test]
+fn test_fvt_round_trip() {
+    // 「電車でＤ」
+    let text = b"\x93\x64\x8E\xD4\x82\xC5\x82\x63";
+    for magic in [DEND_FVT, D2_FVT, D3_FVT].iter() {
+        let data = sample(magic, &[text]);
+        assert_eq!(round_trip(&data), data);
+        let fvt = Fvt::from_read(&mut &data[..]).unwrap();
+        assert_eq!(fvt.records.len(), 1);
+        assert_eq!(fvt.records[0].text, "電車でＤ");
+        assert_eq!(round_trip(&sample(magic, &[b""])), sample(magic, &[b""]));
+    }
+}
+
+#[test]
+fn test_fvt_round_trip_lossy() {
+    // 无效的 Shift-JIS 字节以及以 UTF-8 BOM 开头的文本
+    for text in [&b"\x82\xFF\x80"[..], &b"\xEF\xBB\xBFabc"[..]].iter() {
+        for magic in [DEND_FVT, D2_FVT, D3_FVT].iter() {
+            let data = sample(magic, &[text]);
+            assert_eq!(round_trip(&data), data);
+        }
+    }
+    // 无法解码的字节转义后保留在文本中，修改其余部分不影响这些字节
+    let mut fvt = Fvt::from_read(&mut &sample(D3_FVT, &[b"\x82\xFFab"])[..]).unwrap();
+    assert_eq!(fvt.records[0].text, "\\x82\\xFFab");
+    fvt.records[0].text = "\\x82\\xFF電".into();
+    let mut encoded = Vec::new();
+    fvt.write_to(&mut encoded).unwrap();
+    assert_eq!(encoded, sample(D3_FVT, &[b"\x82\xFF\x93\x64"]));
+}
+
+#[test]
+fn test_fvt_encoding() {
+    // 「电车」的 GBK 编码
+    let data = sample(D2_FVT, &[b"\xB5\xE7\xB3\xB5"]);
+    let gbk: TextEncoding = "gbk".parse().unwrap();
+    let mut json = Vec::new();
+    let options = DecodeOptions {
+        encoding: gbk,
+        ..Default::default()
+    };
+    decode_with(&mut &data[..], &mut json, &options).unwrap();
+    let fvt: Fvt = serde_json::from_slice(&json).unwrap();
+    assert_eq!((fvt.encoding, fvt.records[0].text.as_str()), (gbk, "电车"));
+    // json 中记录了编码，编码时无需再指定
+    let mut encoded = Vec::new();
+    encode(&mut &json[..], &mut encoded).unwrap();
+    assert_eq!(encoded, data);
+
+    // 简体字无法以 Shift-JIS 编码
+    let options = EncodeOptions {
+        encoding: Some(TextEncoding::default()),
+        strict: true,
+        ..Default::default()
+    };
+    let error = encode_with(&mut &json[..], &mut Vec::new(), &options).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "1 problems found\n    $.records[0].text: Characters \"电车\" can't be encoded in Shift_JIS"
+    );
+    // 默认的 Shift-JIS 不写入 json
+    let mut json = Vec::new();
+    decode(&mut &sample(D2_FVT, &[b"abc"])[..], &mut json).unwrap();
+    assert!(!String::from_utf8(json).unwrap().contains("encoding"));
+}
+
+#[test]
+fn test_fvt_legacy_json() {
+    // 旧版本 `fvt decode` 的输出，每个文件只有一条记录
+    let json = br#"{
+  "tag": "D3_FVT",
+  "u32_unknown0": 1,
+  "u32_unknown1": 2,
+  "u32_unknown2": 3,
+  "u32_unknown3": 0,
+  "u8_unknown0": 4,
+  "u8_unknown1": 5,
+  "text": "\u96fb\u8eca"
+}"#;
+    let mut encoded = Vec::new();
+    encode(&mut &json[..], &mut encoded).unwrap();
+    let mut expected = D3_FVT.to_vec();
+    expected.extend_from_slice(&[1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4, 4, 5]);
+    expected.extend_from_slice(b"\x93\x64\x8E\xD4");
+    assert_eq!(encoded, expected);
+    let fvt: Fvt = serde_json::from_slice(json).unwrap();
+    assert_eq!(fvt.records.len(), 1);
+    assert_eq!(fvt.encoding, TextEncoding::default());
+}
+
+#[test]
+fn test_fvt_records() {
+    for magic in [DEND_FVT, D2_FVT, D3_FVT].iter() {
+        let data = sample(magic, &[b"first", b"", b"third"]);
+        let fvt = Fvt::from_read(&mut &data[..]).unwrap();
+        let texts: Vec<&str> = fvt.records.iter().map(|r| r.text.as_str()).collect();
+        assert_eq!(texts, ["first", "", "third"]);
+        assert_eq!(round_trip(&data), data);
+        assert_eq!(round_trip(&sample(magic, &[])), sample(magic, &[]));
+    }
+    // 不完整的记录
+    let mut data = sample(D3_FVT, &[b"first"]);
+    data.extend_from_slice(b"\x00\x01tail");
+    let error = Fvt::from_read(&mut &data[..]).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "6 trailing bytes at offset 26 can't be parsed as a fvt record"
+    );
+}
+
+#[test]
+fn test_fvt_overflow() {
+    // 「電」为 2 字节，200 个即 400 字节
+    let long = "電".repeat(200);
+    let fvt = Fvt {
+        tag: "D3_FVT".into(),
+        records: vec![FvtRecord {
+            u32_unknown0: 9,
+            text: format!("a{}", long),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let error = fvt.write_to(&mut Vec::new()).unwrap_err().to_string();
+    assert_eq!(
+        error,
+        "Text of record 0 is 401 bytes in Shift_JIS, longer than the limit of 255 bytes"
+    );
+
+    let mut truncated = fvt.clone();
+    truncated.fit_text(OverflowPolicy::Truncate);
+    assert_eq!(truncated.records.len(), 1);
+    assert_eq!(truncated.records[0].text, format!("a{}", "電".repeat(127)));
+    truncated.write_to(&mut Vec::new()).unwrap();
+
+    let mut split = fvt.clone();
+    split.fit_text(OverflowPolicy::Split);
+    let texts: Vec<&str> = split.records.iter().map(|r| r.text.as_str()).collect();
+    assert_eq!(texts.concat(), fvt.records[0].text);
+    assert_eq!(texts.len(), 2);
+    assert!(split.records.iter().all(|r| r.u32_unknown0 == 9));
+    split.write_to(&mut Vec::new()).unwrap();
+
+    let mut fits = Fvt {
+        tag: "D3_FVT".into(),
+        records: vec![FvtRecord {
+            text: "電".repeat(127),
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let before = fits.clone();
+    fits.fit_text(OverflowPolicy::Split);
+    assert_eq!(fits, before);
+}
+
+#[test]
+fn test_fvt_magic() {
+    let mut data = sample(D2_FVT, &[b"abc"]);
+    data[2] = b'X';
+    assert!(Fvt::from_read(&mut &data[..]).is_err());
+    let data = sample(b"DX_FVT", &[b"abc"]);
+    assert!(Fvt::from_read(&mut &data[..]).is_err());
+}
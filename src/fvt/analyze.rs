@@ -0,0 +1,222 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 统计字幕文件中各个未知字段的取值，帮助推测它们的含义
+
+use anyhow::Result;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use super::batch::is_fvt_file;
+use super::{Fvt, FvtRecord};
+use crate::text::TextEncoding;
+
+/// 参与统计的字段，`text_length` 为编码后的字节数
+const FIELDS: [&str; 6] = [
+    "u32_unknown0",
+    "u32_unknown1",
+    "u32_unknown2",
+    "u8_unknown0",
+    "u8_unknown1",
+    "text_length",
+];
+
+fn field_values(record: &FvtRecord, encoding: TextEncoding) -> [u32; 6] {
+    let text_length = encoding.encode(&record.text).0.len();
+    [
+        record.u32_unknown0,
+        record.u32_unknown1,
+        record.u32_unknown2,
+        record.u8_unknown0 as u32,
+        record.u8_unknown1 as u32,
+        text_length as u32,
+    ]
+}
+
+#[derive(Debug, Default)]
+pub struct FieldStats {
+    pub name: &'static str,
+    pub values: BTreeMap<u32, usize>,
+    /// 在多记录文件中随记录顺序不减的文件数，时间类字段通常如此
+    pub non_decreasing_files: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Analysis {
+    pub files: usize,
+    pub records: usize,
+    pub multi_record_files: usize,
+    pub failures: Vec<(PathBuf, String)>,
+    pub fields: Vec<FieldStats>,
+    samples: Vec<[u32; 6]>,
+}
+
+impl Analysis {
+    pub fn new() -> Self {
+        Self {
+            fields: FIELDS
+                .iter()
+                .map(|&name| FieldStats {
+                    name,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, fvt: &Fvt) {
+        self.files += 1;
+        self.records += fvt.records.len();
+        let values: Vec<[u32; 6]> = fvt
+            .records
+            .iter()
+            .map(|record| field_values(record, fvt.encoding))
+            .collect();
+        if values.len() > 1 {
+            self.multi_record_files += 1;
+        }
+        for (i, field) in self.fields.iter_mut().enumerate() {
+            for v in &values {
+                *field.values.entry(v[i]).or_default() += 1;
+            }
+            if values.len() > 1 && values.windows(2).all(|w| w[0][i] <= w[1][i]) {
+                field.non_decreasing_files += 1;
+            }
+        }
+        self.samples.extend(values);
+    }
+
+    /// 两个字段之间的皮尔逊相关系数，任一字段为常量时为 `None`
+    pub fn correlation(&self, a: usize, b: usize) -> Option<f64> {
+        let n = self.samples.len() as f64;
+        let mean = |i: usize| self.samples.iter().map(|v| v[i] as f64).sum::<f64>() / n;
+        let (mean_a, mean_b) = (mean(a), mean(b));
+        let (mut cov, mut var_a, mut var_b) = (0.0, 0.0, 0.0);
+        for v in &self.samples {
+            let (da, db) = (v[a] as f64 - mean_a, v[b] as f64 - mean_b);
+            cov += da * db;
+            var_a += da * da;
+            var_b += db * db;
+        }
+        if var_a == 0.0 || var_b == 0.0 {
+            None
+        } else {
+            Some(cov / (var_a * var_b).sqrt())
+        }
+    }
+}
+
+impl fmt::Display for Analysis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} files, {} records, {} files with multiple records, {} failures",
+            self.files,
+            self.records,
+            self.multi_record_files,
+            self.failures.len()
+        )?;
+        for (path, error) in &self.failures {
+            writeln!(f, "    failed {}: {}", path.display(), error)?;
+        }
+        for field in &self.fields {
+            writeln!(f)?;
+            let (min, max) = match (field.values.keys().next(), field.values.keys().last()) {
+                (Some(min), Some(max)) => (*min, *max),
+                _ => continue,
+            };
+            writeln!(
+                f,
+                "{}: min {} max {} ({:#X}..={:#X}), {} distinct, non-decreasing in {}/{} files",
+                field.name,
+                min,
+                max,
+                min,
+                max,
+                field.values.len(),
+                field.non_decreasing_files,
+                self.multi_record_files
+            )?;
+            let mut common: Vec<(&u32, &usize)> = field.values.iter().collect();
+            common.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            for (value, count) in common.iter().take(10) {
+                writeln!(f, "    {:>10} {:#010X} x {}", value, value, count)?;
+            }
+        }
+        writeln!(f)?;
+        writeln!(f, "correlations:")?;
+        for (a, name_a) in FIELDS.iter().enumerate() {
+            for (b, name_b) in FIELDS.iter().enumerate().skip(a + 1) {
+                if let Some(r) = self.correlation(a, b) {
+                    writeln!(f, "    {:>12} ~ {:<12} {:+.3}", name_a, name_b, r)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 统计目录下所有字幕文件，与 `batch` 一样按魔数识别
+pub fn analyze_dir(dir: &Path, encoding: TextEncoding) -> Result<Analysis> {
+    let mut analysis = Analysis::new();
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let result = is_fvt_file(path).and_then(|is_fvt| {
+            if is_fvt {
+                analysis.add(&Fvt::from_read_with(&mut File::open(path)?, encoding)?);
+            }
+            Ok(())
+        });
+        if let Err(error) = result {
+            analysis
+                .failures
+                .push((path.to_path_buf(), error.to_string()));
+        }
+    }
+    Ok(analysis)
+}
+
+#[test]
+fn test_analysis() {
+    let record = |time: u32, text: &str| FvtRecord {
+        u32_unknown0: time,
+        u32_unknown1: 5,
+        u8_unknown0: (time / 10) as u8,
+        text: text.into(),
+        ..Default::default()
+    };
+    let mut analysis = Analysis::new();
+    analysis.add(&Fvt {
+        tag: "D3_FVT".into(),
+        records: vec![record(10, "a"), record(20, "bb"), record(30, "ccc")],
+        ..Default::default()
+    });
+    assert_eq!(analysis.records, 3);
+    assert_eq!(analysis.fields[0].non_decreasing_files, 1);
+    assert_eq!(analysis.fields[1].values.len(), 1);
+    assert!((analysis.correlation(0, 3).unwrap() - 1.0).abs() < 1e-9);
+    assert!((analysis.correlation(0, 5).unwrap() - 1.0).abs() < 1e-9);
+    assert_eq!(analysis.correlation(0, 1), None);
+}
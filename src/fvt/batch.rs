@@ -0,0 +1,212 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 批量解编码目录或 Pack 文件中的字幕文件
+//!
+//! 按文件开头的标识而不是扩展名识别字幕，解码结果在输出目录中保持相同的目录结构，
+//! 文件名追加 `.json`（或 `.toml`、`.yaml`），编码时去掉这一后缀即可得到原文件名。
+
+use anyhow::Result;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use super::{decode_with, encode_with, has_magic, DataFormat, DecodeOptions, EncodeOptions};
+use crate::kcap::{entry_path, KCAPPackReader};
+
+#[derive(Debug, Default)]
+pub struct BatchReport {
+    pub converted: usize,
+    pub skipped: usize,
+    pub failures: Vec<(String, String)>,
+}
+
+impl fmt::Display for BatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} converted, {} skipped, {} failed",
+            self.converted,
+            self.skipped,
+            self.failures.len()
+        )?;
+        for (name, error) in &self.failures {
+            writeln!(f, "    failed {}: {}", name, error)?;
+        }
+        Ok(())
+    }
+}
+
+impl BatchReport {
+    pub(crate) fn record(&mut self, name: String, result: Result<()>) {
+        match result {
+            Ok(()) => self.converted += 1,
+            Err(error) => self.failures.push((name, error.to_string())),
+        }
+    }
+}
+
+fn append_extension(path: &Path, format: DataFormat) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(format.extension());
+    PathBuf::from(name)
+}
+
+/// 转换成功后才写出文件，失败时不留下不完整的输出
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+pub(crate) fn is_fvt_file(path: &Path) -> Result<bool> {
+    let mut head = Vec::with_capacity(8);
+    File::open(path)?.take(8).read_to_end(&mut head)?;
+    Ok(has_magic(&head))
+}
+
+/// 解码目录下所有字幕文件
+pub fn decode_dir(input: &Path, output: &Path, options: &DecodeOptions) -> Result<BatchReport> {
+    let mut report = BatchReport::default();
+    for entry in walkdir::WalkDir::new(input) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let relative = path.strip_prefix(input)?;
+        match is_fvt_file(path) {
+            Ok(true) => {}
+            Ok(false) => {
+                report.skipped += 1;
+                continue;
+            }
+            Err(error) => {
+                report
+                    .failures
+                    .push((relative.display().to_string(), error.to_string()));
+                continue;
+            }
+        }
+        println!("Decoding {}", relative.display());
+        let result = (|| {
+            let mut json = Vec::new();
+            decode_with(&mut File::open(path)?, &mut json, options)?;
+            write_file(
+                &append_extension(&output.join(relative), options.format),
+                &json,
+            )
+        })();
+        report.record(relative.display().to_string(), result);
+    }
+    Ok(report)
+}
+
+/// 直接解码 Pack 文件中的所有字幕条目
+pub fn decode_pack(
+    pack: &mut KCAPPackReader,
+    output: &Path,
+    options: &DecodeOptions,
+) -> Result<BatchReport> {
+    let mut report = BatchReport::default();
+    for i in 0..pack.entries.len() {
+        let name = pack.entries[i].name.clone();
+        match pack.read_head(i, 8) {
+            Ok(head) if has_magic(&head) => {}
+            Ok(_) => {
+                report.skipped += 1;
+                continue;
+            }
+            Err(error) => {
+                report.failures.push((name, error.to_string()));
+                continue;
+            }
+        }
+        println!("Decoding {}", name);
+        let result = (|| {
+            let mut data = Vec::new();
+            pack.read_to(i, &mut data)?;
+            let mut json = Vec::new();
+            decode_with(&mut &data[..], &mut json, options)?;
+            write_file(
+                &append_extension(&output.join(entry_path(&name)), options.format),
+                &json,
+            )
+        })();
+        report.record(name, result);
+    }
+    Ok(report)
+}
+
+/// 将 `decode_dir` / `decode_pack` 生成的目录编码回字幕文件，各文件的格式按扩展名判断
+pub fn encode_dir(input: &Path, output: &Path, options: &EncodeOptions) -> Result<BatchReport> {
+    let mut report = BatchReport::default();
+    for entry in walkdir::WalkDir::new(input) {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(input)?;
+        let format = match DataFormat::from_path(path) {
+            Some(format) if entry.file_type().is_file() => format,
+            _ => {
+                if entry.file_type().is_file() {
+                    report.skipped += 1;
+                }
+                continue;
+            }
+        };
+        let target = output.join(relative.with_extension(""));
+        println!("Encoding {}", relative.display());
+        let result = (|| {
+            let mut fvt = Vec::new();
+            let options = EncodeOptions {
+                format,
+                ..options.clone()
+            };
+            encode_with(&mut File::open(path)?, &mut fvt, &options)?;
+            write_file(&target, &fvt)
+        })();
+        report.record(relative.display().to_string(), result);
+    }
+    Ok(report)
+}
+
+#[test]
+fn test_batch_round_trip() {
+    let root = std::env::temp_dir().join(format!("denshaded-fvt-batch-{}", std::process::id()));
+    let (fvt_dir, json_dir, out_dir) = (root.join("fvt"), root.join("json"), root.join("out"));
+    std::fs::create_dir_all(fvt_dir.join("scene")).unwrap();
+    let fvt = b"D2_FVT\x01\0\0\0\x02\0\0\0\x03\0\0\0\x04\x03\x05abc";
+    std::fs::write(fvt_dir.join("scene").join("no_extension"), &fvt[..]).unwrap();
+    std::fs::write(fvt_dir.join("broken.FVT"), b"D3_FVT\x01").unwrap();
+    std::fs::write(fvt_dir.join("image.png"), b"\x89PNG").unwrap();
+
+    let report = decode_dir(&fvt_dir, &json_dir, &DecodeOptions::default()).unwrap();
+    assert_eq!((report.converted, report.skipped), (1, 1));
+    assert_eq!(report.failures.len(), 1);
+    assert_eq!(report.failures[0].0, "broken.FVT");
+
+    let report = encode_dir(&json_dir, &out_dir, &EncodeOptions::default()).unwrap();
+    assert_eq!((report.converted, report.failures.len()), (1, 0));
+    let encoded = std::fs::read(out_dir.join("scene").join("no_extension")).unwrap();
+    assert_eq!(encoded, &fvt[..]);
+    std::fs::remove_dir_all(&root).unwrap();
+}
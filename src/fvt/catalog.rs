@@ -0,0 +1,520 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 将整个游戏的字幕文本汇总到一个 CSV / gettext PO / XLIFF 翻译目录中
+//!
+//! 每条记录以 `条目路径#记录序号` 为键，路径统一使用 `/` 分隔，
+//! 因此从 Pack 导出的目录可以直接导入到解包后的目录。空文本不会导出。
+
+use anyhow::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::batch::{is_fvt_file, write_file, BatchReport};
+use super::{has_magic, EncodeOptions, Fvt};
+use crate::kcap::KCAPPackReader;
+use crate::text::TextEncoding;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CatalogFormat {
+    Csv,
+    Po,
+    Xliff,
+}
+
+impl FromStr for CatalogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(Self::Csv),
+            "po" | "pot" => Ok(Self::Po),
+            "xliff" | "xlf" => Ok(Self::Xliff),
+            _ => Err(Error::msg(format!("Unknown catalog format: {}", s))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub key: String,
+    pub source: String,
+    pub target: String,
+}
+
+fn path_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn entries_of<'a>(key: &str, fvt: &'a Fvt) -> impl Iterator<Item = CatalogEntry> + 'a {
+    let key = key.to_string();
+    fvt.records
+        .iter()
+        .enumerate()
+        .filter(|(_, record)| !record.text.is_empty())
+        .map(move |(i, record)| CatalogEntry {
+            key: format!("{}#{}", key, i),
+            source: record.text.clone(),
+            target: String::new(),
+        })
+}
+
+/// 收集目录下所有字幕文件的文本
+pub fn collect_dir(dir: &Path, encoding: TextEncoding) -> Result<(Vec<CatalogEntry>, BatchReport)> {
+    let mut entries = Vec::new();
+    let mut report = BatchReport::default();
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let key = path_key(entry.path().strip_prefix(dir)?);
+        let result = is_fvt_file(entry.path()).and_then(|is_fvt| {
+            if is_fvt {
+                let fvt = Fvt::from_read_with(&mut File::open(entry.path())?, encoding)?;
+                entries.extend(entries_of(&key, &fvt));
+            }
+            Ok(is_fvt)
+        });
+        match result {
+            Ok(false) => report.skipped += 1,
+            Ok(true) => report.converted += 1,
+            Err(error) => report.failures.push((key, error.to_string())),
+        }
+    }
+    Ok((entries, report))
+}
+
+/// 收集 Pack 文件中所有字幕条目的文本
+pub fn collect_pack(
+    pack: &mut KCAPPackReader,
+    encoding: TextEncoding,
+) -> Result<(Vec<CatalogEntry>, BatchReport)> {
+    let mut entries = Vec::new();
+    let mut report = BatchReport::default();
+    for i in 0..pack.entries.len() {
+        let key = path_key(&crate::kcap::entry_path(&pack.entries[i].name));
+        let result = pack.read_head(i, 8).and_then(|head| {
+            if has_magic(&head) {
+                let mut data = Vec::new();
+                pack.read_to(i, &mut data)?;
+                entries.extend(entries_of(
+                    &key,
+                    &Fvt::from_read_with(&mut &data[..], encoding)?,
+                ));
+            }
+            Ok(has_magic(&head))
+        });
+        match result {
+            Ok(false) => report.skipped += 1,
+            Ok(true) => report.converted += 1,
+            Err(error) => report.failures.push((key, error.to_string())),
+        }
+    }
+    Ok((entries, report))
+}
+
+fn po_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t")
+}
+
+fn po_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some('t') => out.push('\t'),
+            Some(c) => out.push(c),
+            None => {}
+        }
+    }
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub fn write_catalog(entries: &[CatalogEntry], format: CatalogFormat) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match format {
+        CatalogFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut out);
+            writer.write_record(["key", "source", "target"])?;
+            for entry in entries {
+                writer.write_record([&entry.key, &entry.source, &entry.target])?;
+            }
+            writer.flush()?;
+        }
+        CatalogFormat::Po => {
+            let mut po = String::from(
+                "msgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n",
+            );
+            for entry in entries {
+                po.push_str(&format!(
+                    "\n#: {}\nmsgctxt \"{}\"\nmsgid \"{}\"\nmsgstr \"{}\"\n",
+                    entry.key,
+                    po_escape(&entry.key),
+                    po_escape(&entry.source),
+                    po_escape(&entry.target)
+                ));
+            }
+            out = po.into_bytes();
+        }
+        CatalogFormat::Xliff => {
+            let mut xliff = String::from(concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<xliff version=\"1.2\" xmlns=\"urn:oasis:names:tc:xliff:document:1.2\">\n",
+                "  <file original=\"fvt\" source-language=\"ja\" datatype=\"plaintext\">\n",
+                "    <body>\n"
+            ));
+            for entry in entries {
+                xliff.push_str(&format!(
+                    concat!(
+                        "      <trans-unit id=\"{}\" xml:space=\"preserve\">\n",
+                        "        <source>{}</source>\n",
+                        "        <target>{}</target>\n",
+                        "      </trans-unit>\n"
+                    ),
+                    xml_escape(&entry.key),
+                    xml_escape(&entry.source),
+                    xml_escape(&entry.target)
+                ));
+            }
+            xliff.push_str("    </body>\n  </file>\n</xliff>\n");
+            out = xliff.into_bytes();
+        }
+    }
+    Ok(out)
+}
+
+fn read_po(po: &str) -> Result<Vec<CatalogEntry>> {
+    let mut entries = Vec::new();
+    // msgctxt、msgid、msgstr，续行追加到最近出现的字段
+    let mut fields: [Option<String>; 3] = [None, None, None];
+    let mut last = None;
+    let mut flush = |fields: &mut [Option<String>; 3]| {
+        if let (Some(key), Some(source)) = (fields[0].take(), fields[1].take()) {
+            entries.push(CatalogEntry {
+                key,
+                source,
+                target: fields[2].take().unwrap_or_default(),
+            });
+        }
+        *fields = [None, None, None];
+    };
+    for line in po.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (index, rest) = if let Some(rest) = line.strip_prefix("msgctxt ") {
+            flush(&mut fields);
+            (0, rest)
+        } else if let Some(rest) = line.strip_prefix("msgid ") {
+            (1, rest)
+        } else if let Some(rest) = line.strip_prefix("msgstr ") {
+            (2, rest)
+        } else if line.starts_with('"') {
+            match last {
+                Some(index) => (index, line),
+                None => return Err(Error::msg(format!("Invalid PO line: {}", line))),
+            }
+        } else {
+            return Err(Error::msg(format!("Invalid PO line: {}", line)));
+        };
+        let rest = rest.trim();
+        if rest.len() < 2 || !rest.starts_with('"') || !rest.ends_with('"') {
+            return Err(Error::msg(format!("Invalid PO line: {}", line)));
+        }
+        fields[index]
+            .get_or_insert_with(String::new)
+            .push_str(&po_unescape(&rest[1..rest.len() - 1]));
+        last = Some(index);
+    }
+    flush(&mut fields);
+    Ok(entries)
+}
+
+fn read_xliff(xliff: &str) -> Result<Vec<CatalogEntry>> {
+    let document = roxmltree::Document::parse(xliff)?;
+    let text_of = |node: roxmltree::Node, name: &str| -> String {
+        node.children()
+            .find(|child| child.tag_name().name() == name)
+            .map(|child| {
+                child
+                    .descendants()
+                    .filter(|n| n.is_text())
+                    .filter_map(|n| n.text())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    document
+        .descendants()
+        .filter(|node| node.tag_name().name() == "trans-unit")
+        .map(|unit| {
+            let key = unit
+                .attribute("id")
+                .ok_or_else(|| Error::msg("trans-unit without id"))?;
+            Ok(CatalogEntry {
+                key: key.to_string(),
+                source: text_of(unit, "source"),
+                target: text_of(unit, "target"),
+            })
+        })
+        .collect()
+}
+
+pub fn read_catalog(data: &[u8], format: CatalogFormat) -> Result<Vec<CatalogEntry>> {
+    match format {
+        CatalogFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(data);
+            let mut entries = Vec::new();
+            for record in reader.records() {
+                let record = record?;
+                let field = |i: usize| record.get(i).unwrap_or_default().to_string();
+                entries.push(CatalogEntry {
+                    key: field(0),
+                    source: field(1),
+                    target: field(2),
+                });
+            }
+            Ok(entries)
+        }
+        CatalogFormat::Po => read_po(std::str::from_utf8(data)?),
+        CatalogFormat::Xliff => read_xliff(std::str::from_utf8(data)?),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ApplyReport {
+    pub files: usize,
+    pub applied: usize,
+    /// 原文与目录中的原文不一致而未写入的条目
+    pub source_changed: Vec<(String, String, String)>,
+    /// 目录中有但找不到对应记录的条目
+    pub missing: Vec<String>,
+    pub failures: Vec<(String, String)>,
+}
+
+impl fmt::Display for ApplyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} texts applied to {} files, {} source changed, {} missing, {} failed",
+            self.applied,
+            self.files,
+            self.source_changed.len(),
+            self.missing.len(),
+            self.failures.len()
+        )?;
+        for (key, expected, actual) in &self.source_changed {
+            writeln!(
+                f,
+                "    source changed {}: {:?} -> {:?}",
+                key, expected, actual
+            )?;
+        }
+        for key in &self.missing {
+            writeln!(f, "    missing {}", key)?;
+        }
+        for (key, error) in &self.failures {
+            writeln!(f, "    failed {}: {}", key, error)?;
+        }
+        Ok(())
+    }
+}
+
+/// 将译文写回目录中的字幕文件，修改过的文件写入输出目录的相同位置
+///
+/// 原文件按 `encoding` 读取，写出时的编码可由 `options` 覆盖。
+pub fn apply_dir(
+    input: &Path,
+    output: &Path,
+    entries: &[CatalogEntry],
+    encoding: TextEncoding,
+    options: &EncodeOptions,
+) -> Result<ApplyReport> {
+    let catalog: HashMap<&str, &CatalogEntry> = entries
+        .iter()
+        .map(|entry| (entry.key.as_str(), entry))
+        .collect();
+    let mut seen = HashSet::new();
+    let mut report = ApplyReport::default();
+    for entry in walkdir::WalkDir::new(input) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(input)?;
+        let file_key = path_key(relative);
+        let result = (|| -> Result<()> {
+            if !is_fvt_file(entry.path())? {
+                return Ok(());
+            }
+            let mut fvt = Fvt::from_read_with(&mut File::open(entry.path())?, encoding)?;
+            let mut applied = 0;
+            for (i, record) in fvt.records.iter_mut().enumerate() {
+                let key = format!("{}#{}", file_key, i);
+                let translated = match catalog.get(key.as_str()) {
+                    Some(translated) => translated,
+                    None => continue,
+                };
+                seen.insert(translated.key.as_str());
+                if translated.target.is_empty() || translated.target == record.text {
+                    continue;
+                }
+                if translated.source != record.text {
+                    report.source_changed.push((
+                        key,
+                        translated.source.clone(),
+                        record.text.clone(),
+                    ));
+                    continue;
+                }
+                record.text = translated.target.clone();
+                applied += 1;
+            }
+            if applied > 0 {
+                fvt.prepare(options);
+                let mut data = Vec::new();
+                fvt.write_with(&mut data, options.strict)?;
+                write_file(&output.join(relative), &data)?;
+                report.files += 1;
+                report.applied += applied;
+            }
+            Ok(())
+        })();
+        if let Err(error) = result {
+            report.failures.push((file_key, error.to_string()));
+        }
+    }
+    report.missing = entries
+        .iter()
+        .filter(|entry| !seen.contains(entry.key.as_str()))
+        .map(|entry| entry.key.clone())
+        .collect();
+    Ok(report)
+}
+
+#[test]
+fn test_catalog_formats() {
+    let entries = vec![
+        CatalogEntry {
+            key: "data/a.FVT#0".into(),
+            source: "電車でＤ \"引用\", <tag> & \\ 改\n行".into(),
+            target: String::new(),
+        },
+        CatalogEntry {
+            key: "data/a.FVT#2".into(),
+            source: "二".into(),
+            target: "Two\tTabs".into(),
+        },
+    ];
+    for &format in &[CatalogFormat::Csv, CatalogFormat::Po, CatalogFormat::Xliff] {
+        let data = write_catalog(&entries, format).unwrap();
+        assert_eq!(
+            read_catalog(&data, format).unwrap(),
+            entries,
+            "{:?}",
+            format
+        );
+    }
+    // 续行形式的 PO
+    let po = "msgid \"\"\nmsgstr \"\"\n\n#, fuzzy\nmsgctxt \"k#0\"\nmsgid \"\"\n\"a\"\n\"b\"\nmsgstr \"x\"\n\"y\"\n";
+    let entries = read_catalog(po.as_bytes(), CatalogFormat::Po).unwrap();
+    assert_eq!(
+        entries,
+        [CatalogEntry {
+            key: "k#0".into(),
+            source: "ab".into(),
+            target: "xy".into()
+        }]
+    );
+}
+
+#[test]
+fn test_catalog_apply() {
+    use super::FvtRecord;
+
+    let root = std::env::temp_dir().join(format!("denshaded-fvt-catalog-{}", std::process::id()));
+    let dir = root.join("data");
+    std::fs::create_dir_all(&dir).unwrap();
+    let record = |text: &str| FvtRecord {
+        text: text.into(),
+        ..Default::default()
+    };
+    let fvt = Fvt {
+        tag: "D3_FVT".into(),
+        records: vec![record("一"), record(""), record("三")],
+        ..Default::default()
+    };
+    let mut data = Vec::new();
+    fvt.write_to(&mut data).unwrap();
+    std::fs::write(dir.join("a.FVT"), &data).unwrap();
+
+    let (mut entries, report) = collect_dir(&root, TextEncoding::default()).unwrap();
+    assert_eq!(report.converted, 1);
+    let keys: Vec<&str> = entries.iter().map(|e| e.key.as_str()).collect();
+    assert_eq!(keys, ["data/a.FVT#0", "data/a.FVT#2"]);
+
+    entries[0].target = "One".into();
+    entries[1].source = "参".into();
+    entries[1].target = "Three".into();
+    entries.push(CatalogEntry {
+        key: "data/b.FVT#0".into(),
+        source: "x".into(),
+        target: "y".into(),
+    });
+    let report = apply_dir(
+        &root,
+        &root,
+        &entries,
+        TextEncoding::default(),
+        &EncodeOptions::default(),
+    )
+    .unwrap();
+    assert_eq!((report.files, report.applied), (1, 1));
+    assert_eq!(report.source_changed.len(), 1);
+    assert_eq!(report.source_changed[0].0, "data/a.FVT#2");
+    assert_eq!(report.missing, ["data/b.FVT#0"]);
+
+    let applied = Fvt::from_read(&mut File::open(dir.join("a.FVT")).unwrap()).unwrap();
+    assert_eq!(applied.records[0].text, "One");
+    assert_eq!(applied.records[2].text, "三");
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,147 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 在各作品的字幕格式之间转换
+//!
+//! 各变体共有 `u32_unknown0` 与两个 u8 字段，Lightning Stage 没有 `u32_unknown1` 与 `u32_unknown2`。
+
+use anyhow::{Error, Result};
+
+use super::subtitle::field;
+#[cfg(test)]
+use super::FvtRecord;
+use super::{Fvt, FvtVariant};
+
+/// 转换到有三个 u32 字段的变体时，缺少的字段所用的值
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ConvertDefaults {
+    pub u32_unknown1: u32,
+    pub u32_unknown2: u32,
+}
+
+impl ConvertDefaults {
+    /// 解析 `u32_unknown1=100` 形式的赋值
+    pub fn set(&mut self, assignment: &str) -> Result<()> {
+        let (field, value) = assignment
+            .split_once('=')
+            .ok_or_else(|| Error::msg(format!("Expected FIELD=VALUE: {}", assignment)))?;
+        let value = value
+            .parse()
+            .map_err(|_| Error::msg(format!("Invalid u32 value: {}", value)))?;
+        match super::subtitle::parse_field(field)? {
+            1 => self.u32_unknown1 = value,
+            2 => self.u32_unknown2 = value,
+            _ => {
+                return Err(Error::msg(format!(
+                    "{} is shared by every variant and has no default",
+                    field
+                )))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Fvt {
+    /// 转换为另一变体，返回丢弃数据的警告
+    pub fn convert(&mut self, to: FvtVariant, defaults: &ConvertDefaults) -> Result<Vec<String>> {
+        let from = FvtVariant::from_tag(&self.tag)
+            .ok_or_else(|| Error::msg(format!("Unknown fvt type: {}", self.tag)))?;
+        let mut warnings = Vec::new();
+        match (from.u32_fields(), to.u32_fields()) {
+            (1, 3) => {
+                for record in &mut self.records {
+                    record.u32_unknown1 = defaults.u32_unknown1;
+                    record.u32_unknown2 = defaults.u32_unknown2;
+                }
+            }
+            (3, 1) => {
+                for (name, index) in [("u32_unknown1", 1), ("u32_unknown2", 2)].iter() {
+                    let dropped: Vec<(usize, u32)> = self
+                        .records
+                        .iter()
+                        .map(|record| field(record, *index))
+                        .enumerate()
+                        .filter(|(_, v)| *v != 0)
+                        .collect();
+                    if let Some((index, value)) = dropped.first() {
+                        warnings.push(format!(
+                            "{} of {} records is dropped, it isn't zero (e.g. record {}: {})",
+                            name,
+                            dropped.len(),
+                            index,
+                            value
+                        ));
+                    }
+                }
+                for record in &mut self.records {
+                    record.u32_unknown1 = 0;
+                    record.u32_unknown2 = 0;
+                }
+            }
+            _ => {}
+        }
+        self.tag = to.tag().into();
+        Ok(warnings)
+    }
+}
+
+#[test]
+fn test_convert() {
+    let record = |a: u32, b: u32| FvtRecord {
+        u32_unknown0: 10,
+        u32_unknown1: a,
+        u32_unknown2: b,
+        u8_unknown0: 1,
+        text: "電車".into(),
+        ..Default::default()
+    };
+    let d3 = Fvt {
+        tag: "D3_FVT".into(),
+        records: vec![record(0, 0), record(5, 0), record(7, 0)],
+        ..Default::default()
+    };
+
+    let mut lightning = d3.clone();
+    let warnings = lightning
+        .convert(FvtVariant::Lightning, &Default::default())
+        .unwrap();
+    assert_eq!(
+        warnings,
+        ["u32_unknown1 of 2 records is dropped, it isn't zero (e.g. record 1: 5)"]
+    );
+    assert_eq!(lightning.tag, "DEND_FVT");
+    let mut data = Vec::new();
+    lightning.write_to(&mut data).unwrap();
+    assert_eq!(Fvt::from_read(&mut &data[..]).unwrap(), lightning);
+
+    let mut defaults = ConvertDefaults::default();
+    defaults.set("u32_unknown2=42").unwrap();
+    assert!(defaults.set("u32_unknown0=1").is_err());
+    let mut back = lightning.clone();
+    assert!(back
+        .convert(FvtVariant::Burning, &defaults)
+        .unwrap()
+        .is_empty());
+    assert_eq!(back.tag, "D2_FVT");
+    assert!(back
+        .records
+        .iter()
+        .all(|r| (r.u32_unknown0, r.u32_unknown1, r.u32_unknown2) == (10, 0, 42)));
+    assert_eq!(back.records[0].text, "電車");
+}
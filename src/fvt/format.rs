@@ -0,0 +1,116 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 字幕的文本表示：JSON、TOML 或 YAML
+//!
+//! 读取时都先转为 `serde_json::Value`，以便统一校验。
+
+use anyhow::{Error, Result};
+use serde_json::Value;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::Fvt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum DataFormat {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl FromStr for DataFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "toml" => Ok(Self::Toml),
+            "yaml" | "yml" => Ok(Self::Yaml),
+            _ => Err(Error::msg(format!("Unknown data format: {}", s))),
+        }
+    }
+}
+
+impl DataFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Toml => "toml",
+            Self::Yaml => "yaml",
+        }
+    }
+
+    /// 按扩展名判断格式
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+
+    pub fn to_vec(&self, fvt: &Fvt) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Json => serde_json::to_vec_pretty(fvt)?,
+            Self::Toml => toml::to_string_pretty(fvt)?.into_bytes(),
+            Self::Yaml => serde_yaml::to_string(fvt)?.into_bytes(),
+        })
+    }
+
+    pub fn to_value(&self, data: &[u8]) -> Result<Value> {
+        Ok(match self {
+            Self::Json => serde_json::from_slice(data)?,
+            Self::Toml => toml::from_slice(data)?,
+            Self::Yaml => serde_yaml::from_slice(data)?,
+        })
+    }
+}
+
+#[test]
+fn test_data_formats() {
+    use super::FvtRecord;
+
+    let fvt = Fvt {
+        tag: "D3_FVT".into(),
+        encoding: "gbk".parse().unwrap(),
+        records: vec![
+            FvtRecord {
+                u32_unknown0: u32::MAX,
+                u8_unknown1: 255,
+                text: "二行の\n字幕".into(),
+                ..Default::default()
+            },
+            FvtRecord::default(),
+        ],
+    };
+    for format in [DataFormat::Json, DataFormat::Toml, DataFormat::Yaml].iter() {
+        let data = format.to_vec(&fvt).unwrap();
+        let value = format.to_value(&data).unwrap();
+        assert_eq!(
+            serde_json::from_value::<Fvt>(value).unwrap(),
+            fvt,
+            "{:?}",
+            format
+        );
+    }
+    let toml = String::from_utf8(DataFormat::Toml.to_vec(&fvt).unwrap()).unwrap();
+    assert!(toml.contains("[[records]]"));
+    assert_eq!(
+        DataFormat::from_path(Path::new("a/b.YML")),
+        Some(DataFormat::Yaml)
+    );
+    assert_eq!(DataFormat::from_path(Path::new("a/b.FVT")), None);
+}
@@ -0,0 +1,405 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! FVT json 的 JSON Schema 与编码前的校验
+//!
+//! 校验一次报告所有问题，路径形如 `$.records[3].text`。
+
+use serde_json::{json, Map, Value};
+use std::fmt;
+
+use super::{EncodeOptions, FvtVariant, OverflowPolicy, MAX_TEXT_LENGTH};
+use crate::text::TextEncoding;
+
+const U32_FIELDS: [&str; 3] = ["u32_unknown0", "u32_unknown1", "u32_unknown2"];
+const U8_FIELDS: [&str; 2] = ["u8_unknown0", "u8_unknown1"];
+
+/// `fvt decode` 输出的 JSON Schema (draft-07)
+pub fn schema() -> Value {
+    let mut record = Map::new();
+    for name in U32_FIELDS.iter() {
+        record.insert(
+            name.to_string(),
+            json!({ "type": "integer", "minimum": 0, "maximum": u32::MAX }),
+        );
+    }
+    for name in U8_FIELDS.iter() {
+        record.insert(
+            name.to_string(),
+            json!({ "type": "integer", "minimum": 0, "maximum": u8::MAX }),
+        );
+    }
+    record.insert(
+        "text".into(),
+        json!({
+            "type": "string",
+            "description": format!(
+                "At most {} bytes once encoded, unless split or truncated: 2 bytes for most Japanese characters and 1 for ASCII in Shift-JIS. Bytes that can't be decoded are written as \\xHH and count as one byte",
+                MAX_TEXT_LENGTH
+            ),
+        }),
+    );
+    let variants: Vec<Value> = FvtVariant::ALL
+        .iter()
+        .map(|variant| {
+            let mut then = json!({});
+            if variant.u32_fields() == 1 {
+                then = json!({
+                    "properties": { "records": { "items": { "properties": {
+                        "u32_unknown1": { "const": 0, "description": "Not stored in this variant" },
+                        "u32_unknown2": { "const": 0, "description": "Not stored in this variant" },
+                    }}}}
+                });
+            }
+            json!({
+                "if": { "properties": { "tag": { "const": variant.tag() } } },
+                "then": then,
+            })
+        })
+        .collect();
+    let tags: Vec<&str> = FvtVariant::ALL
+        .iter()
+        .map(|variant| variant.tag())
+        .collect();
+    let descriptions: Vec<String> = FvtVariant::ALL
+        .iter()
+        .map(|variant| format!("{}: {}", variant.tag(), variant.game()))
+        .collect();
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "FVT subtitle",
+        "type": "object",
+        "required": ["tag", "records"],
+        "additionalProperties": false,
+        "properties": {
+            "tag": { "enum": tags, "description": descriptions.join(", ") },
+            "encoding": {
+                "type": "string",
+                "default": TextEncoding::default().to_string(),
+                "description": "Text encoding, such as Shift_JIS, GBK or UTF-8",
+            },
+            "records": { "type": "array", "items": { "$ref": "#/definitions/record" } },
+        },
+        "allOf": variants,
+        "definitions": {
+            "record": {
+                "type": "object",
+                "required": U32_FIELDS.iter().chain(U8_FIELDS.iter()).chain(["text"].iter()).collect::<Vec<_>>(),
+                "additionalProperties": false,
+                "properties": record,
+            }
+        }
+    })
+}
+
+/// 校验发现的一个问题
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+struct Validator {
+    problems: Vec<Problem>,
+}
+
+impl Validator {
+    fn report(&mut self, path: &str, message: String) {
+        self.problems.push(Problem {
+            path: path.into(),
+            message,
+        });
+    }
+
+    /// 检查对象的字段，返回字段表
+    fn object<'a>(
+        &mut self,
+        path: &str,
+        value: &'a Value,
+        known: &[&str],
+    ) -> Option<&'a Map<String, Value>> {
+        let object = match value.as_object() {
+            Some(object) => object,
+            None => {
+                self.report(path, format!("Expected an object, found {}", value));
+                return None;
+            }
+        };
+        for key in object.keys() {
+            if !known.contains(&key.as_str()) {
+                self.report(&format!("{}.{}", path, key), "Unknown field".into());
+            }
+        }
+        Some(object)
+    }
+
+    fn integer(&mut self, path: &str, value: Option<&Value>, max: u64) -> Option<u64> {
+        match value {
+            None => self.report(path, "Missing field".into()),
+            Some(value) => match value.as_u64() {
+                Some(n) if n <= max => return Some(n),
+                _ => self.report(
+                    path,
+                    format!("{} is not an integer in range 0..={}", value, max),
+                ),
+            },
+        }
+        None
+    }
+}
+
+/// 旧版本导出的 json 没有 `records`，tag 与唯一一条记录的字段平铺在同一个对象中
+fn is_legacy(value: &Value) -> bool {
+    value.get("records").is_none() && value.get("text").is_some()
+}
+
+/// 旧版本总是输出为 0、编码时忽略的字段
+const LEGACY_UNSTORED: &str = "u32_unknown3";
+
+impl Validator {
+    /// 检查一条记录的字段与文本
+    fn record(
+        &mut self,
+        path: &str,
+        record: &Map<String, Value>,
+        variant: Option<FvtVariant>,
+        encoding: TextEncoding,
+        options: &EncodeOptions,
+    ) {
+        for (index, name) in U32_FIELDS.iter().enumerate() {
+            let field_path = format!("{}.{}", path, name);
+            let value = self.integer(&field_path, record.get(*name), u32::MAX as u64);
+            let stored = variant.map(|variant| index < variant.u32_fields());
+            if let (Some(n), Some(false)) = (value, stored) {
+                if n != 0 {
+                    self.report(
+                        &field_path,
+                        format!(
+                            "{} is not stored in {} and would be lost, use fvt convert",
+                            n,
+                            variant.unwrap().tag()
+                        ),
+                    );
+                }
+            }
+        }
+        for name in U8_FIELDS.iter() {
+            self.integer(
+                &format!("{}.{}", path, name),
+                record.get(*name),
+                u8::MAX as u64,
+            );
+        }
+
+        let text_path = format!("{}.text", path);
+        let text = match record.get("text") {
+            None => {
+                self.report(&text_path, "Missing field".into());
+                return;
+            }
+            Some(Value::String(text)) => text,
+            Some(value) => {
+                self.report(&text_path, format!("Expected a string, found {}", value));
+                return;
+            }
+        };
+        let text = match &options.charmap {
+            Some(charmap) => charmap.apply_encoding(encoding, text),
+            None => text.clone(),
+        };
+        let (bytes, unmappable) = encoding.encode(&text);
+        if options.strict && !unmappable.is_empty() {
+            self.report(
+                &text_path,
+                format!(
+                    "Characters {:?} can't be encoded in {}",
+                    unmappable.iter().collect::<String>(),
+                    encoding
+                ),
+            );
+        }
+        if options.overflow == OverflowPolicy::Error && bytes.len() > MAX_TEXT_LENGTH {
+            self.report(
+                &text_path,
+                format!(
+                    "{} bytes in {}, longer than the limit of {} bytes",
+                    bytes.len(),
+                    encoding,
+                    MAX_TEXT_LENGTH
+                ),
+            );
+        }
+    }
+}
+
+/// 按编码选项校验 json，返回所有问题
+///
+/// 也接受旧版本导出的只有一条记录的平铺格式，见 `is_legacy`。
+pub fn validate(value: &Value, options: &EncodeOptions) -> Vec<Problem> {
+    let mut v = Validator {
+        problems: Vec::new(),
+    };
+    let record_fields: Vec<&str> = U32_FIELDS
+        .iter()
+        .chain(U8_FIELDS.iter())
+        .chain(["text"].iter())
+        .copied()
+        .collect();
+    let legacy = is_legacy(value);
+    let known: Vec<&str> = if legacy {
+        let mut known = vec!["tag", LEGACY_UNSTORED];
+        known.extend(&record_fields);
+        known
+    } else {
+        vec!["tag", "encoding", "records"]
+    };
+    let root = match v.object("$", value, &known) {
+        Some(root) => root,
+        None => return v.problems,
+    };
+
+    let variant = match root.get("tag") {
+        None => {
+            v.report("$.tag", "Missing field".into());
+            None
+        }
+        Some(tag) => {
+            let variant = tag.as_str().and_then(FvtVariant::from_tag);
+            if variant.is_none() {
+                v.report("$.tag", format!("Unknown fvt type {}", tag));
+            }
+            variant
+        }
+    };
+    let mut encoding = TextEncoding::default();
+    if let Some(value) = root.get("encoding") {
+        match value.as_str().map(str::parse::<TextEncoding>) {
+            Some(Ok(parsed)) => encoding = parsed,
+            Some(Err(error)) => v.report("$.encoding", error.to_string()),
+            None => v.report("$.encoding", format!("Expected a string, found {}", value)),
+        }
+    }
+    let encoding = options.encoding.unwrap_or(encoding);
+
+    if legacy {
+        if let Some(value) = root.get(LEGACY_UNSTORED) {
+            if value.as_u64() != Some(0) {
+                v.report(
+                    &format!("$.{}", LEGACY_UNSTORED),
+                    format!("{} is not stored in FVT files and would be lost", value),
+                );
+            }
+        }
+        v.record("$", root, variant, encoding, options);
+        return v.problems;
+    }
+
+    let records = match root.get("records").map(Value::as_array) {
+        Some(Some(records)) => records,
+        Some(None) => {
+            v.report("$.records", "Expected an array".into());
+            return v.problems;
+        }
+        None => {
+            v.report("$.records", "Missing field".into());
+            return v.problems;
+        }
+    };
+    for (i, record) in records.iter().enumerate() {
+        let path = format!("$.records[{}]", i);
+        if let Some(record) = v.object(&path, record, &record_fields) {
+            v.record(&path, record, variant, encoding, options);
+        }
+    }
+    v.problems
+}
+
+#[test]
+fn test_validate() {
+    let record = json!({
+        "u32_unknown0": 1, "u32_unknown1": 0, "u32_unknown2": 0,
+        "u8_unknown0": 2, "u8_unknown1": 3, "text": "電車",
+    });
+    let valid = json!({ "tag": "DEND_FVT", "records": [record] });
+    assert_eq!(validate(&valid, &EncodeOptions::default()), []);
+
+    let mut invalid = valid.clone();
+    invalid["encoding"] = json!("EBCDIC");
+    invalid["records"][0]["u8_unknown0"] = json!(300);
+    invalid["records"][0]["u32_unknown1"] = json!(5);
+    invalid["records"][0]["txt"] = json!("typo");
+    invalid["records"][0]["text"] = json!("电".repeat(200));
+    invalid["records"].as_array_mut().unwrap().push(json!("x"));
+    let paths: Vec<String> = validate(&invalid, &EncodeOptions::default())
+        .into_iter()
+        .map(|problem| problem.path)
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "$.encoding",
+            "$.records[0].txt",
+            "$.records[0].u32_unknown1",
+            "$.records[0].u8_unknown0",
+            "$.records[0].text",
+            "$.records[1]",
+        ]
+    );
+    // 超长文本在拆分策略下不是问题，严格模式下无法编码的字符是问题
+    let options = EncodeOptions {
+        overflow: OverflowPolicy::Split,
+        strict: true,
+        ..Default::default()
+    };
+    invalid["encoding"] = json!("Shift_JIS");
+    let problems = validate(&invalid, &options);
+    assert_eq!(problems[3].path, "$.records[0].text");
+    assert!(problems[3].message.starts_with("Characters"));
+
+    // 旧版本导出的平铺格式
+    let legacy = json!({
+        "tag": "D3_FVT", "u32_unknown0": 1, "u32_unknown1": 2, "u32_unknown2": 3, "u32_unknown3": 0,
+        "u8_unknown0": 2, "u8_unknown1": 3, "text": "電車",
+    });
+    assert_eq!(validate(&legacy, &EncodeOptions::default()), []);
+    let mut invalid = legacy;
+    invalid["u32_unknown3"] = json!(7);
+    invalid["u8_unknown1"] = json!(-1);
+    let paths: Vec<String> = validate(&invalid, &EncodeOptions::default())
+        .into_iter()
+        .map(|problem| problem.path)
+        .collect();
+    assert_eq!(paths, ["$.u32_unknown3", "$.u8_unknown1"]);
+
+    let schema = schema();
+    assert_eq!(
+        schema["definitions"]["record"]["properties"]["u8_unknown0"]["maximum"],
+        255
+    );
+    assert_eq!(schema["properties"]["tag"]["enum"][2], "D3_FVT");
+    // 长度限制按编码后的字节数计算，字符数无法表达
+    assert!(schema["definitions"]["record"]["properties"]["text"]
+        .get("maxLength")
+        .is_none());
+}
@@ -0,0 +1,346 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 将字幕导出为 SRT / ASS / WebVTT 以便在字幕编辑器中翻译，并导入回 FVT
+//!
+//! 字幕格式无法表示的字段保存在旁车 JSON 中（即 `fvt decode` 的输出），
+//! 导入时以旁车为基础，只替换文本与映射到时间轴的字段。
+
+use anyhow::{Error, Result};
+use std::fmt::Write;
+use std::str::FromStr;
+
+use super::{Fvt, FvtRecord};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SubtitleFormat {
+    Srt,
+    Ass,
+    Vtt,
+}
+
+impl FromStr for SubtitleFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "srt" => Ok(Self::Srt),
+            "ass" | "ssa" => Ok(Self::Ass),
+            "vtt" | "webvtt" => Ok(Self::Vtt),
+            _ => Err(Error::msg(format!("Unknown subtitle format: {}", s))),
+        }
+    }
+}
+
+/// 作为开始/结束时间（毫秒）的 u32 字段序号，对应 `u32_unknown0..=2`
+///
+/// 未映射结束时间时，以下一条记录的开始时间或开始后 `DEFAULT_DURATION` 毫秒作为结束。
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TimingMap {
+    pub start: usize,
+    pub end: Option<usize>,
+}
+
+const DEFAULT_DURATION: u32 = 3000;
+
+/// 解析 `u32_unknown0` 或 `0` 形式的字段名
+pub fn parse_field(name: &str) -> Result<usize> {
+    let index = name.trim_start_matches("u32_unknown");
+    match index.parse() {
+        Ok(index) if index < 3 => Ok(index),
+        _ => Err(Error::msg(format!("Unknown u32 field: {}", name))),
+    }
+}
+
+pub(crate) fn field(record: &FvtRecord, index: usize) -> u32 {
+    match index {
+        0 => record.u32_unknown0,
+        1 => record.u32_unknown1,
+        2 => record.u32_unknown2,
+        _ => panic!("Unknown u32 field: {}", index),
+    }
+}
+
+fn field_mut(record: &mut FvtRecord, index: usize) -> &mut u32 {
+    match index {
+        0 => &mut record.u32_unknown0,
+        1 => &mut record.u32_unknown1,
+        2 => &mut record.u32_unknown2,
+        _ => panic!("Unknown u32 field: {}", index),
+    }
+}
+
+fn format_time(ms: u32, format: SubtitleFormat) -> String {
+    let (h, m, s, ms) = (ms / 3600000, ms / 60000 % 60, ms / 1000 % 60, ms % 1000);
+    match format {
+        SubtitleFormat::Srt => format!("{:02}:{:02}:{:02},{:03}", h, m, s, ms),
+        SubtitleFormat::Vtt => format!("{:02}:{:02}:{:02}.{:03}", h, m, s, ms),
+        SubtitleFormat::Ass => format!("{}:{:02}:{:02}.{:02}", h, m, s, ms / 10),
+    }
+}
+
+fn parse_time(time: &str) -> Result<u32> {
+    let error = || Error::msg(format!("Invalid timestamp: {}", time));
+    let time = time.trim();
+    let (hms, frac) = match time.rfind([',', '.']) {
+        Some(pos) => (&time[..pos], &time[pos + 1..]),
+        None => (time, "0"),
+    };
+    let mut ms = 0u32;
+    for part in hms.split(':') {
+        let part = part.parse::<u32>().map_err(|_| error())?;
+        ms = ms
+            .checked_mul(60)
+            .and_then(|ms| ms.checked_add(part))
+            .ok_or_else(error)?;
+    }
+    // 小数部分按位数换算，ASS 为百分之一秒
+    let frac_ms = match frac.len() {
+        1 => frac.parse::<u32>().map_err(|_| error())? * 100,
+        2 => frac.parse::<u32>().map_err(|_| error())? * 10,
+        3 => frac.parse::<u32>().map_err(|_| error())?,
+        _ => return Err(error()),
+    };
+    ms.checked_mul(1000)
+        .and_then(|ms| ms.checked_add(frac_ms))
+        .ok_or_else(error)
+}
+
+fn cue_times(fvt: &Fvt, timing: &TimingMap) -> Vec<(u32, u32)> {
+    let records = &fvt.records;
+    records
+        .iter()
+        .enumerate()
+        .map(|(i, record)| {
+            let start = field(record, timing.start);
+            let end = match timing.end {
+                Some(end) => field(record, end),
+                None => records
+                    .get(i + 1)
+                    .map(|next| field(next, timing.start))
+                    .filter(|&next| next > start)
+                    .unwrap_or_else(|| start.saturating_add(DEFAULT_DURATION)),
+            };
+            (start, end)
+        })
+        .collect()
+}
+
+pub fn export(fvt: &Fvt, format: SubtitleFormat, timing: &TimingMap) -> String {
+    let mut out = String::new();
+    let times = cue_times(fvt, timing);
+    match format {
+        SubtitleFormat::Srt | SubtitleFormat::Vtt => {
+            if format == SubtitleFormat::Vtt {
+                out.push_str("WEBVTT\n\n");
+            }
+            for (i, (record, (start, end))) in fvt.records.iter().zip(times).enumerate() {
+                let _ = writeln!(out, "{}", i + 1);
+                let _ = writeln!(
+                    out,
+                    "{} --> {}",
+                    format_time(start, format),
+                    format_time(end, format)
+                );
+                let _ = writeln!(out, "{}\n", record.text);
+            }
+        }
+        SubtitleFormat::Ass => {
+            out.push_str("[Script Info]\nScriptType: v4.00+\n\n");
+            out.push_str("[V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n");
+            out.push_str("Style: Default,MS Gothic,40,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,0,2,10,10,10,128\n\n");
+            out.push_str("[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n");
+            for (record, (start, end)) in fvt.records.iter().zip(times) {
+                let _ = writeln!(
+                    out,
+                    "Dialogue: 0,{},{},Default,,0,0,0,,{}",
+                    format_time(start, format),
+                    format_time(end, format),
+                    record.text.replace('\n', "\\N")
+                );
+            }
+        }
+    }
+    out
+}
+
+/// 从字幕中读出的一条记录：开始、结束时间与文本
+fn parse_cues(subtitle: &str, format: SubtitleFormat) -> Result<Vec<(u32, u32, String)>> {
+    let subtitle = subtitle
+        .trim_start_matches('\u{FEFF}')
+        .replace("\r\n", "\n");
+    let mut cues = Vec::new();
+    match format {
+        SubtitleFormat::Srt | SubtitleFormat::Vtt => {
+            // 按时间行切分，文本中可以有空行
+            let lines: Vec<&str> = subtitle.lines().collect();
+            let timings: Vec<usize> = (0..lines.len())
+                .filter(|&i| lines[i].contains("-->"))
+                .collect();
+            for (n, &i) in timings.iter().enumerate() {
+                let timing = lines[i];
+                let mut times = timing.split("-->");
+                let start = parse_time(times.next().unwrap_or_default())?;
+                // WebVTT 的时间后可以跟随设置项
+                let end = times
+                    .next()
+                    .and_then(|end| end.split_whitespace().next())
+                    .ok_or_else(|| Error::msg(format!("Invalid cue timing: {}", timing)))?;
+                let end = parse_time(end)?;
+                // 下一条的时间行之前是它的序号，再之前是分隔的空行
+                let mut text = match timings.get(n + 1) {
+                    Some(&next) if next > i + 1 && !lines[next - 1].is_empty() => {
+                        &lines[i + 1..next - 1]
+                    }
+                    Some(&next) => &lines[i + 1..next],
+                    None => &lines[i + 1..],
+                };
+                if let Some((last, rest)) = text.split_last() {
+                    if last.is_empty() {
+                        text = rest;
+                    }
+                }
+                cues.push((start, end, text.join("\n")));
+            }
+        }
+        SubtitleFormat::Ass => {
+            for line in subtitle.lines() {
+                if let Some(dialogue) = line.strip_prefix("Dialogue:") {
+                    let fields: Vec<&str> = dialogue.splitn(10, ',').collect();
+                    if fields.len() < 10 {
+                        return Err(Error::msg(format!("Invalid dialogue line: {}", line)));
+                    }
+                    let text = fields[9].replace("\\N", "\n");
+                    cues.push((parse_time(fields[1])?, parse_time(fields[2])?, text));
+                }
+            }
+        }
+    }
+    Ok(cues)
+}
+
+/// 将字幕中的文本与时间写回旁车中的 FVT
+///
+/// 时间与导出时的显示一致时保留原值，因此未修改的字幕可以原样还原。
+pub fn import(
+    fvt: &mut Fvt,
+    subtitle: &str,
+    format: SubtitleFormat,
+    timing: &TimingMap,
+) -> Result<()> {
+    let cues = parse_cues(subtitle, format)?;
+    if cues.len() != fvt.records.len() {
+        return Err(Error::msg(format!(
+            "Subtitle has {} cues but the FVT has {} records",
+            cues.len(),
+            fvt.records.len()
+        )));
+    }
+    let times = cue_times(fvt, timing);
+    let same = |a: u32, b: u32| format_time(a, format) == format_time(b, format);
+    for ((record, (start, end, text)), (old_start, old_end)) in
+        fvt.records.iter_mut().zip(cues).zip(times)
+    {
+        if !same(start, old_start) {
+            *field_mut(record, timing.start) = start;
+        }
+        if let Some(end_field) = timing.end {
+            if !same(end, old_end) {
+                *field_mut(record, end_field) = end;
+            }
+        }
+        record.text = text;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn sample_fvt() -> Fvt {
+    let record = |start: u32, end: u32, text: &str| FvtRecord {
+        u32_unknown0: start,
+        u32_unknown1: end,
+        u32_unknown2: 42,
+        u8_unknown0: 3,
+        u8_unknown1: 9,
+        text: text.into(),
+    };
+    Fvt {
+        tag: "D3_FVT".into(),
+        records: vec![
+            record(1234, 3456, "電車でＤ"),
+            record(3661001, 3662999, "二行の\n字幕"),
+            record(4000000, 4000005, ""),
+            record(4000010, 4000020, "空行を\n\n含む字幕"),
+            record(4000030, 4000040, "最後"),
+        ],
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_subtitle_round_trip() {
+    let fvt = sample_fvt();
+    for &format in &[
+        SubtitleFormat::Srt,
+        SubtitleFormat::Ass,
+        SubtitleFormat::Vtt,
+    ] {
+        for timing in &[
+            TimingMap::default(),
+            TimingMap {
+                start: 0,
+                end: Some(1),
+            },
+        ] {
+            let subtitle = export(&fvt, format, timing);
+            let mut imported = fvt.clone();
+            import(&mut imported, &subtitle, format, timing).unwrap();
+            assert_eq!(imported, fvt, "{:?}\n{}", format, subtitle);
+            let mut imported = fvt.clone();
+            let crlf = subtitle.replace('\n', "\r\n");
+            import(&mut imported, &crlf, format, timing).unwrap();
+            assert_eq!(imported, fvt, "{:?}\n{}", format, crlf);
+        }
+    }
+}
+
+#[test]
+fn test_subtitle_import_edit() {
+    let mut fvt = sample_fvt();
+    let timing = TimingMap {
+        start: 0,
+        end: Some(1),
+    };
+    let subtitle = export(&fvt, SubtitleFormat::Srt, &timing)
+        .replace("電車でＤ", "Densha de D")
+        .replace("00:00:01,234", "00:00:02,000");
+    import(&mut fvt, &subtitle, SubtitleFormat::Srt, &timing).unwrap();
+    assert_eq!(fvt.records[0].text, "Densha de D");
+    assert_eq!(fvt.records[0].u32_unknown0, 2000);
+    assert_eq!(fvt.records[0].u32_unknown1, 3456);
+    assert_eq!(fvt.records[0].u32_unknown2, 42);
+
+    let ass = "Dialogue: 0,0:00:01.50,0:00:02.00,Default,,0,0,0,,a, b\\Nc\n";
+    let cues = parse_cues(ass, SubtitleFormat::Ass).unwrap();
+    assert_eq!(cues, [(1500, 2000, "a, b\nc".to_string())]);
+    assert!(import(&mut fvt, ass, SubtitleFormat::Ass, &timing).is_err());
+
+    assert_eq!(parse_time("01:01:01,001").unwrap(), 3661001);
+    assert!(parse_time("99999:00:00,000").is_err());
+    assert!(parse_time("4294967:00,000").is_err());
+}
@@ -0,0 +1,246 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 识别字幕文件的格式变体
+//!
+//! 标识第二个字节区分各作品：`E` 为 Lightning Stage，`2` 为 Burning Stage，`3` 为 Climax Stage 与 Rising Stage。
+
+use anyhow::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+use super::{D2_FVT, D3_FVT, DEND_FVT};
+
+/// 识别未知变体时转储的头部字节数
+const DUMP_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FvtVariant {
+    Lightning,
+    Burning,
+    ClimaxRising,
+}
+
+impl FvtVariant {
+    pub const ALL: [Self; 3] = [Self::Lightning, Self::Burning, Self::ClimaxRising];
+
+    /// 按标识的第二个字节判断变体
+    pub fn from_type_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0x45 => Some(Self::Lightning),
+            0x32 => Some(Self::Burning),
+            0x33 => Some(Self::ClimaxRising),
+            _ => None,
+        }
+    }
+
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|variant| variant.magic() == tag.as_bytes())
+    }
+
+    pub fn magic(&self) -> &'static [u8] {
+        match self {
+            Self::Lightning => DEND_FVT,
+            Self::Burning => D2_FVT,
+            Self::ClimaxRising => D3_FVT,
+        }
+    }
+
+    pub fn tag(&self) -> &'static str {
+        std::str::from_utf8(self.magic()).unwrap()
+    }
+
+    pub fn game(&self) -> &'static str {
+        match self {
+            Self::Lightning => "Lightning Stage",
+            Self::Burning => "Burning Stage",
+            Self::ClimaxRising => "Climax Stage / Rising Stage",
+        }
+    }
+
+    /// 每条记录开头的 u32 字段数
+    pub fn u32_fields(&self) -> usize {
+        match self {
+            Self::Lightning => 1,
+            Self::Burning | Self::ClimaxRising => 3,
+        }
+    }
+
+    /// 一条记录的布局：偏移、类型与字段名，文本长度由 `text_length` 给出
+    pub fn record_layout(&self) -> Vec<(usize, &'static str, &'static str)> {
+        let mut layout: Vec<_> = ["u32_unknown0", "u32_unknown1", "u32_unknown2"]
+            .iter()
+            .take(self.u32_fields())
+            .enumerate()
+            .map(|(i, name)| (i * 4, "u32", *name))
+            .collect();
+        let offset = self.u32_fields() * 4;
+        layout.push((offset, "u8", "u8_unknown0"));
+        layout.push((offset + 1, "u8", "text_length"));
+        layout.push((offset + 2, "u8", "u8_unknown1"));
+        layout.push((offset + 3, "[u8]", "text"));
+        layout
+    }
+}
+
+impl fmt::Display for FvtVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Variant: {} ({})", self.tag(), self.game())?;
+        writeln!(
+            f,
+            "Header: magic {:?}, {} bytes",
+            self.tag(),
+            self.magic().len()
+        )?;
+        writeln!(f, "Record, repeated until the end of file:")?;
+        for (offset, kind, name) in self.record_layout() {
+            writeln!(f, "    +{:<3} {:<5} {}", offset, kind, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FvtVariant {
+    type Err = Error;
+
+    /// 接受标识、作品名或简称，如 `D3_FVT`、`climax`、`d3`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "dend_fvt" | "dend" | "e" | "lightning" => Ok(Self::Lightning),
+            "d2_fvt" | "d2" | "2" | "burning" => Ok(Self::Burning),
+            "d3_fvt" | "d3" | "3" | "climax" | "rising" => Ok(Self::ClimaxRising),
+            _ => Err(Error::msg(format!("Unknown fvt variant: {}", s))),
+        }
+    }
+}
+
+/// `detect` 的结果
+#[derive(Debug, Clone, PartialEq)]
+pub enum Detection {
+    Known(FvtVariant),
+    /// 未知的变体，保留头部字节以便分析
+    Unknown {
+        /// 在头部找到的形如 `*_FVT` 的标识
+        magic: Option<String>,
+        head: Vec<u8>,
+    },
+}
+
+/// 根据文件开头的字节识别变体，`data` 只需包含头部
+pub fn detect(data: &[u8]) -> Detection {
+    if let Some(variant) = FvtVariant::ALL
+        .iter()
+        .find(|variant| data.starts_with(variant.magic()))
+    {
+        return Detection::Known(*variant);
+    }
+    let head = &data[..data.len().min(DUMP_LENGTH)];
+    let magic = head
+        .windows(4)
+        .position(|window| window == b"_FVT")
+        .map(|end| String::from_utf8_lossy(&head[..end + 4]).into_owned());
+    Detection::Unknown {
+        magic,
+        head: head.to_vec(),
+    }
+}
+
+/// 每行 16 字节的十六进制转储
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        let ascii: String = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        dump.push_str(&format!(
+            "{:08X}  {:<47}  |{}|\n",
+            i * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+    dump
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Known(variant) => write!(f, "{}", variant),
+            Self::Unknown { magic, head } => {
+                match magic {
+                    Some(magic) => writeln!(f, "Variant: unknown, magic {:?}", magic)?,
+                    None => writeln!(f, "Variant: unknown, no *_FVT magic found")?,
+                }
+                writeln!(f, "Header ({} bytes):", head.len())?;
+                write!(f, "{}", hex_dump(head))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_detect() {
+    assert_eq!(
+        detect(b"DEND_FVT\x01\0\0\0"),
+        Detection::Known(FvtVariant::Lightning)
+    );
+    assert_eq!(detect(D3_FVT), Detection::Known(FvtVariant::ClimaxRising));
+    assert_eq!(FvtVariant::from_tag("D2_FVT"), Some(FvtVariant::Burning));
+    assert_eq!(
+        "d3".parse::<FvtVariant>().unwrap(),
+        FvtVariant::ClimaxRising
+    );
+    assert_eq!(
+        "Lightning".parse::<FvtVariant>().unwrap(),
+        FvtVariant::Lightning
+    );
+    assert_eq!(
+        FvtVariant::from_type_byte(b'3'),
+        Some(FvtVariant::ClimaxRising)
+    );
+
+    let layout = FvtVariant::Burning.record_layout();
+    assert_eq!(layout[3], (12, "u8", "u8_unknown0"));
+    assert_eq!(
+        FvtVariant::Lightning.record_layout()[2],
+        (5, "u8", "text_length")
+    );
+
+    match detect(b"D4_FVT\x01\x02") {
+        Detection::Unknown { magic, head } => {
+            assert_eq!(magic.as_deref(), Some("D4_FVT"));
+            assert_eq!(head.len(), 8);
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(
+        hex_dump(b"D4_FVT\x01\x02"),
+        "00000000  44 34 5F 46 56 54 01 02                          |D4_FVT..|\n"
+    );
+}
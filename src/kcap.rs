@@ -22,18 +22,33 @@
 use anyhow::{Error, Result};
 use byteorder::*;
 use encoding_rs::SHIFT_JIS;
+use lazy_static::lazy_static;
 use memmap::Mmap;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::TryInto;
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 use crate::crc32::{self, compute};
+use crate::sniff::{self, FileType};
+use crate::text::TextEncoding;
+
+pub mod archive;
+pub mod converter;
 
 pub type KeyTable = [u8; 0x10000];
 
+/// 条目名编码后的最大字节数
+pub const NAME_LENGTH: usize = 64;
+
 #[derive(Debug)]
 pub struct KCAPEntry {
     pub name: String,
+    /// 条目名的 crc32
+    pub crc32: u32,
     pub offset: usize,
     pub size: usize,
     pub encrypted: bool,
@@ -41,16 +56,22 @@ pub struct KCAPEntry {
 
 impl KCAPEntry {
     pub fn from_read(file: &mut impl Read) -> Result<Self> {
-        let mut buf = [0; 64];
+        Self::from_read_with(file, TextEncoding::default())
+    }
+
+    /// 按 `encoding` 解码条目名，无法解码的字节以 `\xHH` 转义
+    pub fn from_read_with(file: &mut impl Read, encoding: TextEncoding) -> Result<Self> {
+        let mut buf = [0; NAME_LENGTH];
         file.read_exact(&mut buf)?;
-        let (file_name, _, _error) = SHIFT_JIS.decode(&buf);
-        let _crc32 = file.read_u32::<LE>()? as usize;
+        let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+        let crc32 = file.read_u32::<LE>()?;
         let _unknown = file.read_u32::<LE>()?;
         let offset = file.read_u32::<LE>()? as usize;
         let size = file.read_u32::<LE>()? as usize;
         let encrypted = file.read_u32::<LE>()? != 0;
         Ok(Self {
-            name: file_name.trim_end_matches('\0').to_string(),
+            name: encoding.decode(&buf[..len]),
+            crc32,
             offset,
             size,
             encrypted,
@@ -58,15 +79,39 @@ impl KCAPEntry {
     }
 }
 
+/// Pack 中的条目名使用 `\` 分隔，转换为当前系统的路径，并去掉 `.` 与 `..` 以免写出目标目录
+pub fn entry_path(name: &str) -> PathBuf {
+    name.split(['\\', '/'])
+        .filter(|part| !part.is_empty() && *part != "." && *part != "..")
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct KCAPPackReader {
     pub file: File,
     pub entries: Vec<KCAPEntry>,
-    pub key_table: KeyTable,
+    pub key_table: Arc<KeyTable>,
 }
 
 impl<'a> KCAPPackReader {
     pub fn new<P: AsRef<Path>>(path: P, pass: &str) -> Result<Self> {
+        Self::with_key_table(path, cached_key_table(pass))
+    }
+
+    /// 使用预先计算好的密钥表打开 Pack 文件，可传入 `KeyTable` 或共享的 `Arc<KeyTable>`
+    pub fn with_key_table<P: AsRef<Path>>(
+        path: P,
+        key_table: impl Into<Arc<KeyTable>>,
+    ) -> Result<Self> {
+        Self::with_encoding(path, key_table, TextEncoding::default())
+    }
+
+    /// 同 `with_key_table`，条目名按 `encoding` 解码
+    pub fn with_encoding<P: AsRef<Path>>(
+        path: P,
+        key_table: impl Into<Arc<KeyTable>>,
+        encoding: TextEncoding,
+    ) -> Result<Self> {
         let mut file = std::fs::File::open(path)?;
         let mut buf = [0; 4];
         file.read_exact(&mut buf)?;
@@ -76,66 +121,130 @@ impl<'a> KCAPPackReader {
         let file_amount = file.read_i32::<LE>()?;
         let mut entries = Vec::with_capacity(file_amount as usize);
         for _ in 0..file_amount {
-            let entry = KCAPEntry::from_read(&mut file)?;
+            let entry = KCAPEntry::from_read_with(&mut file, encoding)?;
             entries.push(entry);
         }
         Ok(Self {
             file,
             entries,
-            key_table: create_key_table(pass),
+            key_table: key_table.into(),
         })
     }
 
     pub fn read_to(&'a mut self, index: usize, output: &mut impl Write) -> Result<()> {
         let entry = &self.entries[index];
         let map = unsafe { Mmap::map(&self.file)? };
-        let transformed = (map[entry.offset..entry.offset + entry.size]
-            .iter()
-            .enumerate()
-            .map(|(i, &x)| {
-                if entry.encrypted {
-                    x ^ self.key_table[i % self.key_table.len()]
-                } else {
-                    x
-                }
-            }))
-        .collect::<Vec<u8>>();
-        output.write(&transformed)?;
+        let data = &map[entry.offset..entry.offset + entry.size];
+        if entry.encrypted {
+            write_with_key_table(&self.key_table, data, output)?;
+        } else {
+            output.write_all(data)?;
+        }
         Ok(())
     }
+
+    /// 读取并解密条目开头最多 `len` 个字节，用于判断文件类型
+    pub fn read_head(&self, index: usize, len: usize) -> Result<Vec<u8>> {
+        self.read_range(index, 0, len)
+    }
+
+    /// 读取并解密条目中从 `offset` 开始最多 `len` 个字节
+    pub fn read_range(&self, index: usize, offset: usize, len: usize) -> Result<Vec<u8>> {
+        let entry = &self.entries[index];
+        let offset = offset.min(entry.size);
+        let mut data = vec![0; len.min(entry.size - offset)];
+        read_exact_at(&self.file, &mut data, (entry.offset + offset) as u64)?;
+        let key_table = &self.key_table;
+        if entry.encrypted && offset % key_table.len() == 0 {
+            apply_key_table(key_table, &mut data);
+        } else if entry.encrypted {
+            for (i, x) in data.iter_mut().enumerate() {
+                *x ^= key_table[(offset + i) % key_table.len()];
+            }
+        }
+        Ok(data)
+    }
+
+    /// 按开头的字节识别条目的内容类型
+    pub fn sniff(&self, index: usize) -> Result<FileType> {
+        Ok(sniff::sniff(&self.read_head(index, sniff::SNIFF_LENGTH)?))
+    }
+}
+
+/// 按位置读取，不移动文件指针，因此多个线程可以同时读取同一个 Pack
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// 待写入条目的数据来源
+#[derive(Debug)]
+pub enum KCAPEntryData {
+    File(File),
+    /// 在内存中生成的数据，如转换后的文件
+    Memory(Vec<u8>),
+    /// 另一个 Pack 中的条目，写入时逐块解密
+    Pack(Arc<KCAPPackReader>, usize),
+    /// 共享文件中从偏移处开始的数据，如归档中未压缩存放的文件
+    Range(Arc<File>, u64),
 }
 
 #[derive(Debug)]
 pub struct KCAPEntryWrite {
     pub name: String,
-    pub file: File,
+    pub data: KCAPEntryData,
     pub offset: u64,
     pub size: u64,
 }
 
 #[derive(Debug)]
 pub struct KCAPPackWriter {
+    /// 生成密钥表所用的密码，直接使用密钥表创建时为 `None`
     pub pass: Option<String>,
-    pub key_table: Option<KeyTable>,
+    pub key_table: Option<Arc<KeyTable>>,
+    /// 条目名的编码
+    pub encoding: TextEncoding,
     pub entries: Vec<KCAPEntryWrite>,
 }
 
 impl KCAPPackWriter {
     pub fn new(pass: Option<String>) -> Self {
         Self {
-            key_table: if let Some(pass) = &pass {
-                Some(create_key_table(pass))
-            } else {
-                None
-            },
-            pass,
+            pass: pass.clone(),
+            ..Self::with_key_table(pass.map(|pass| cached_key_table(&pass)))
+        }
+    }
+
+    /// 使用预先计算好的密钥表，为 `None` 时不加密
+    pub fn with_key_table(key_table: Option<Arc<KeyTable>>) -> Self {
+        Self {
+            pass: None,
+            key_table,
+            encoding: TextEncoding::default(),
             entries: Vec::with_capacity(64),
         }
     }
 
     pub fn calc_offset(&mut self) {
-        self.entries.sort_by(|a, b| a.size.cmp(&b.size));
-        let mut file_offset = 8 + self.entries.len() as u64 * (64 + 8 + 4 + 4 + 4);
+        self.entries.sort_by_key(|a| a.size);
+        let mut file_offset = 8 + self.entries.len() as u64 * (NAME_LENGTH as u64 + 8 + 4 + 4 + 4);
         for item in &mut self.entries {
             item.offset = file_offset;
             file_offset += item.size;
@@ -147,24 +256,78 @@ impl KCAPPackWriter {
         let file_meta = file_path.metadata()?;
         self.entries.push(KCAPEntryWrite {
             name: name.into(),
-            file: File::open(file_path)?,
+            data: KCAPEntryData::File(File::open(file_path)?),
             offset: 0,
             size: file_meta.len(),
         });
         Ok(())
     }
 
+    /// 添加内存中的数据作为条目
+    pub fn add_data(&mut self, data: Vec<u8>, name: &str) {
+        self.entries.push(KCAPEntryWrite {
+            name: name.into(),
+            size: data.len() as u64,
+            data: KCAPEntryData::Memory(data),
+            offset: 0,
+        });
+    }
+
+    /// 添加另一个 Pack 中的第 `index` 个条目，不必先解包
+    pub fn add_pack_entry(&mut self, pack: Arc<KCAPPackReader>, index: usize, name: &str) {
+        self.entries.push(KCAPEntryWrite {
+            name: name.into(),
+            size: pack.entries[index].size as u64,
+            data: KCAPEntryData::Pack(pack, index),
+            offset: 0,
+        });
+    }
+
+    /// 添加 `file` 中从 `offset` 开始的 `size` 字节作为条目，多个条目可以共用一个文件
+    pub fn add_file_range(&mut self, file: Arc<File>, offset: u64, size: u64, name: &str) {
+        self.entries.push(KCAPEntryWrite {
+            name: name.into(),
+            data: KCAPEntryData::Range(file, offset),
+            offset: 0,
+            size,
+        });
+    }
+
+    /// 写入临时文件后替换 `path`，因此可以重建正在读取的 Pack 本身
+    pub fn write_file(&mut self, path: &Path) -> Result<()> {
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        File::create(&temp)
+            .map_err(Error::from)
+            .and_then(|mut file| self.write_to(&mut file))
+            .and_then(|_| Ok(std::fs::rename(&temp, path)?))
+            .map_err(|err| {
+                let _ = std::fs::remove_file(&temp);
+                Error::msg(format!("Can't write {}: {}", path.display(), err))
+            })
+    }
+
     pub fn write_to(&mut self, output: &mut impl Write) -> Result<()> {
         self.calc_offset();
-        output.write(b"KCAP")?;
+        output.write_all(b"KCAP")?;
         output.write_i32::<LE>(self.entries.len() as i32)?;
-        let mut buf = [0; 64];
+        let mut buf = [0; NAME_LENGTH];
         let encrypted = if self.key_table.is_some() { 1 } else { 0 };
         for item in &self.entries {
-            let bytes = item.name.as_bytes();
+            let bytes = self.encoding.encode_checked(&item.name, true)?;
+            if bytes.len() > buf.len() {
+                return Err(Error::msg(format!(
+                    "Entry name {:?} is {} bytes in {}, longer than the limit of {} bytes",
+                    item.name,
+                    bytes.len(),
+                    self.encoding,
+                    buf.len()
+                )));
+            }
             buf.fill(0);
             buf[..bytes.len()].clone_from_slice(&bytes);
-            output.write(&buf)?;
+            output.write_all(&buf)?;
             let path_crc = compute(&buf, 0, bytes.len());
             output.write_u32::<LE>(path_crc)?;
             output.write_u32::<LE>(0)?; // 空余的无用 padding？
@@ -173,24 +336,57 @@ impl KCAPPackWriter {
             output.write_u32::<LE>(encrypted)?;
         }
         for item in &mut self.entries {
-            if let Some(key_table) = &self.key_table {
-                let map = unsafe { Mmap::map(&item.file)? };
-                let transformed = (map[0..item.size as usize]
-                    .iter()
-                    .enumerate()
-                    .map(|(i, &x)| x ^ key_table[i % key_table.len()]))
-                .collect::<Vec<u8>>();
-                output.write(&transformed)?;
-            } else {
-                std::io::copy(&mut item.file, output)?;
+            match (&mut item.data, &self.key_table) {
+                // 空文件无法映射
+                _ if item.size == 0 => {}
+                (KCAPEntryData::File(file), Some(key_table)) => {
+                    let map = unsafe { Mmap::map(&*file)? };
+                    write_with_key_table(key_table, &map[0..item.size as usize], output)?;
+                }
+                (KCAPEntryData::File(file), None) => {
+                    std::io::copy(file, output)?;
+                }
+                (KCAPEntryData::Memory(data), Some(key_table)) => {
+                    write_with_key_table(key_table, data, output)?;
+                }
+                (KCAPEntryData::Memory(data), None) => output.write_all(data)?,
+                (KCAPEntryData::Range(file, offset), key_table) => {
+                    let map = unsafe { Mmap::map(file)? };
+                    let data = &map[*offset as usize..(*offset + item.size) as usize];
+                    match key_table {
+                        Some(key_table) => write_with_key_table(key_table, data, output)?,
+                        None => output.write_all(data)?,
+                    }
+                }
+                (KCAPEntryData::Pack(pack, index), key_table) => {
+                    let block = 0x10000;
+                    for offset in (0..item.size as usize).step_by(block) {
+                        let mut data = pack.read_range(*index, offset, block)?;
+                        if let Some(key_table) = key_table {
+                            apply_key_table(key_table, &mut data);
+                        }
+                        output.write_all(&data)?;
+                    }
+                }
             }
         }
         Ok(())
     }
 }
 
+#[test]
+fn test_entry_path() {
+    let expected: PathBuf = ["data", "fvt", "001.FVT"].iter().collect();
+    assert_eq!(entry_path("data\\fvt\\001.FVT"), expected);
+    assert_eq!(entry_path("..\\data/./fvt\\..\\001.FVT"), expected);
+}
+
 #[test]
 fn test_kcap_pack() {
+    // 测试用的游戏数据包不随仓库分发，没有时跳过
+    if !Path::new("./test/DenD_3rd_Data.Pack").exists() {
+        return;
+    }
     println!(
         "{:?}",
         KCAPPackReader::new("./test/DenD_3rd_Data.Pack", "PackPass")
@@ -199,116 +395,424 @@ fn test_kcap_pack() {
     )
 }
 
+#[test]
+fn test_kcap_entry_names() {
+    let root = std::env::temp_dir().join(format!("denshaded-kcap-names-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let data = root.join("data");
+    std::fs::write(&data, b"abc").unwrap();
+    let names = ["字幕\\電車.FVT", "raw\\x82\\xFF.bin", "电车.FVT"];
+    let gbk: TextEncoding = "gbk".parse().unwrap();
+    for (i, (name, encoding)) in names
+        .iter()
+        .zip([TextEncoding::default(), TextEncoding::default(), gbk])
+        .enumerate()
+    {
+        let mut writer = KCAPPackWriter::with_key_table(None);
+        writer.encoding = encoding;
+        writer.add_entry(&data, name).unwrap();
+        let path = root.join(format!("{}.Pack", i));
+        writer.write_to(&mut File::create(&path).unwrap()).unwrap();
+        let reader = KCAPPackReader::with_encoding(&path, [0; 0x10000], encoding).unwrap();
+        assert_eq!(reader.entries[0].name, *name);
+    }
+    // 无法编码的条目名报错
+    let mut writer = KCAPPackWriter::with_key_table(None);
+    writer.add_entry(&data, "电车.FVT").unwrap();
+    assert!(writer.write_to(&mut Vec::new()).is_err());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_read_range() {
+    let root = std::env::temp_dir().join(format!("denshaded-kcap-range-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let data: Vec<u8> = (0..0x12345u32).map(|i| (i * 7 % 251) as u8).collect();
+    let mut writer = KCAPPackWriter::new(Some("PackPass".into()));
+    writer.add_data(data.clone(), "data.bin");
+    let path = root.join("range.Pack");
+    writer.write_to(&mut File::create(&path).unwrap()).unwrap();
+    let reader = KCAPPackReader::new(&path, "PackPass").unwrap();
+    for &(offset, len) in &[
+        (0, 64),
+        (3, 100),
+        (0x10000, 0x2000),
+        (0xFFF0, 0x20),
+        (0x12300, 0x1000),
+    ] {
+        let end = (offset + len).min(data.len());
+        assert_eq!(
+            reader.read_range(0, offset, len).unwrap(),
+            &data[offset..end]
+        );
+    }
+    assert!(reader.read_range(0, data.len() + 1, 8).unwrap().is_empty());
+
+    // 从另一个 Pack 复制条目，换用不同的密钥
+    let mut writer = KCAPPackWriter::new(Some("Other".into()));
+    writer.add_pack_entry(Arc::new(reader), 0, "copy.bin");
+    let path = root.join("copy.Pack");
+    writer.write_to(&mut File::create(&path).unwrap()).unwrap();
+    let copy = KCAPPackReader::new(&path, "Other").unwrap();
+    assert_eq!(copy.read_range(0, 0, data.len()).unwrap(), data);
+
+    // 共用一个暂存文件的条目
+    let spool = root.join("spool");
+    std::fs::write(&spool, b"headtail").unwrap();
+    let spool = Arc::new(File::open(&spool).unwrap());
+    let mut writer = KCAPPackWriter::new(Some("PackPass".into()));
+    writer.add_file_range(spool.clone(), 4, 4, "tail.bin");
+    writer.add_file_range(spool.clone(), 0, 0, "empty.bin");
+    writer.add_file_range(spool, 0, 4, "head.bin");
+    let path = root.join("spool.Pack");
+    writer.write_file(&path).unwrap();
+    let reader = KCAPPackReader::new(&path, "PackPass").unwrap();
+    let read: Vec<_> = (0..reader.entries.len())
+        .map(|i| {
+            (
+                reader.entries[i].name.clone(),
+                reader.read_range(i, 0, 8).unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        read,
+        [
+            ("empty.bin".to_string(), b"".to_vec()),
+            ("tail.bin".to_string(), b"tail".to_vec()),
+            ("head.bin".to_string(), b"head".to_vec()),
+        ]
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
 pub fn passkey_hash(pass: &str) -> u32 {
     let (bytes, _, _err) = encoding_rs::SHIFT_JIS.encode(pass);
     crc32::compute(bytes.as_ref(), 0, bytes.len())
 }
 
 pub fn create_key_table(pass: &str) -> KeyTable {
-    let pass = if pass.len() < 8 {
-        "Selene.Default.Password"
+    let pass = pass_bytes(pass);
+    let seed = crc32::compute(&pass, 0, pass.len());
+    create_key_table_from_bytes(&pass, seed)
+}
+
+lazy_static! {
+    static ref KEY_TABLE_CACHE: Mutex<HashMap<String, Arc<KeyTable>>> = Mutex::new(HashMap::new());
+}
+
+/// 缓存的密钥表数量上限，超出时丢弃任意一个
+const KEY_TABLE_CACHE_LIMIT: usize = 16;
+
+/// 取得密码对应的密钥表，缓存中已有时不再生成
+///
+/// 生成时不持有锁，其他密码的查询不会被阻塞；
+/// 同一密码并发生成时以先写入缓存的为准。
+pub fn cached_key_table(pass: &str) -> Arc<KeyTable> {
+    if let Some(key_table) = KEY_TABLE_CACHE.lock().unwrap().get(pass) {
+        return key_table.clone();
+    }
+    let key_table = Arc::new(create_key_table(pass));
+    let mut cache = KEY_TABLE_CACHE.lock().unwrap();
+    if let Some(key_table) = cache.get(pass) {
+        return key_table.clone();
+    }
+    if cache.len() >= KEY_TABLE_CACHE_LIMIT {
+        if let Some(old) = cache.keys().next().cloned() {
+            cache.remove(&old);
+        }
+    }
+    cache.insert(pass.to_string(), key_table.clone());
+    key_table
+}
+
+/// 引擎以 Shift-JIS 编码后的字节计算密钥，不足 8 字节时改用默认密码
+fn pass_bytes(pass: &str) -> Cow<'_, [u8]> {
+    let (bytes, _, _err) = SHIFT_JIS.encode(pass);
+    if bytes.len() < 8 {
+        Cow::Borrowed(b"Selene.Default.Password")
     } else {
-        pass
-    };
-    let pass_len = pass.len();
-    let seed = passkey_hash(pass);
-    let mut rng = KeyTableGenerator::new(seed as i32);
+        bytes
+    }
+}
+
+/// 使用指定的种子代替密码的 crc32 生成密钥表，密码仍用于混淆
+pub fn create_key_table_with_seed(pass: &str, seed: u32) -> KeyTable {
+    create_key_table_from_bytes(&pass_bytes(pass), seed)
+}
+
+fn create_key_table_from_bytes(pass: &[u8], seed: u32) -> KeyTable {
+    let mut rng = KeyTableGenerator::new(seed);
 
     let mut table = [0; 0x10000];
-    for i in 0..table.len() {
+    for (i, v) in table.iter_mut().enumerate() {
         let key = rng.rand();
-        let pos = (i % pass_len) as usize;
         let m = (key >> 16) as u8;
-        let pass_bytes = pass.as_bytes();
-        let v = pass_bytes[pos] ^ m;
-        table[i] = v;
+        *v = pass[i % pass.len()] ^ m;
     }
     table
 }
 
+/// 将数据与密钥表异或，`data` 的首字节对应密钥表的首字节
+///
+/// 按 u64 宽度处理以便编译器向量化，数据按密钥表的周期分块，每块都与密钥表对齐。
+pub fn apply_key_table(key_table: &KeyTable, data: &mut [u8]) {
+    for block in data.chunks_mut(key_table.len()) {
+        let offset = block.len() / 8 * 8;
+        let mut words = block.chunks_exact_mut(8);
+        let mut keys = key_table.chunks_exact(8);
+        for (word, key) in (&mut words).zip(&mut keys) {
+            let v = u64::from_ne_bytes((&*word).try_into().unwrap())
+                ^ u64::from_ne_bytes(key.try_into().unwrap());
+            word.copy_from_slice(&v.to_ne_bytes());
+        }
+        let rest = words.into_remainder();
+        for (x, k) in rest.iter_mut().zip(&key_table[offset..]) {
+            *x ^= k;
+        }
+    }
+}
+
+/// 逐块解/加密并写出，避免一次性复制整个文件
+fn write_with_key_table(key_table: &KeyTable, data: &[u8], output: &mut impl Write) -> Result<()> {
+    let mut buf = vec![0; key_table.len()];
+    for block in data.chunks(key_table.len()) {
+        let buf = &mut buf[..block.len()];
+        buf.copy_from_slice(block);
+        apply_key_table(key_table, buf);
+        output.write_all(buf)?;
+    }
+    Ok(())
+}
+
+/// 从文件中读取 0x10000 字节的密钥表
+pub fn load_key_table<P: AsRef<Path>>(path: P) -> Result<KeyTable> {
+    let data = std::fs::read(path)?;
+    let mut table = [0; 0x10000];
+    if data.len() != table.len() {
+        return Err(Error::msg(format!(
+            "Key table file must be exactly {} bytes, got {}",
+            table.len(),
+            data.len()
+        )));
+    }
+    table.copy_from_slice(&data);
+    Ok(table)
+}
+
 #[test]
 fn test_key_table() {
     assert_eq!(
         &create_key_table("")[0..16],
         &[43, 153, 246, 46, 115, 3, 156, 205, 107, 241, 77, 219, 216, 177, 13, 71]
     );
+    assert_eq!(
+        create_key_table("PackPass")[..],
+        create_key_table_with_seed("PackPass", passkey_hash("PackPass"))[..]
+    );
+}
+
+#[test]
+fn test_cached_key_table() {
+    let a = cached_key_table("CachedPass");
+    let b = cached_key_table("CachedPass");
+    assert!(Arc::ptr_eq(&a, &b));
+    assert_eq!(a[..], create_key_table("CachedPass")[..]);
+    assert!(!Arc::ptr_eq(&a, &cached_key_table("OtherPass")));
+    for i in 0..KEY_TABLE_CACHE_LIMIT + 4 {
+        cached_key_table(&format!("CachedPass{}", i));
+    }
+    assert!(KEY_TABLE_CACHE.lock().unwrap().len() <= KEY_TABLE_CACHE_LIMIT);
 }
 
+#[test]
+fn test_apply_key_table() {
+    let key_table = create_key_table("PackPass");
+    // 覆盖不足一个字、跨越密钥表周期以及末尾不对齐的情况
+    for &len in &[0usize, 5, 8, 0x10000, 0x10000 * 2 + 13] {
+        let data: Vec<u8> = (0..len).map(|i| (i * 31 + 7) as u8).collect();
+        let expected: Vec<u8> = data
+            .iter()
+            .enumerate()
+            .map(|(i, &x)| x ^ key_table[i % key_table.len()])
+            .collect();
+        let mut applied = data.clone();
+        apply_key_table(&key_table, &mut applied);
+        assert_eq!(applied, expected);
+        let mut written = Vec::new();
+        write_with_key_table(&key_table, &data, &mut written).unwrap();
+        assert_eq!(written, expected);
+    }
+}
+
+#[test]
+fn test_key_table_shift_jis() {
+    // 6 字节的 Shift-JIS 密码，UTF-8 下却有 9 字节，应当回退到默认密码
+    assert_eq!(create_key_table("電車で")[..], create_key_table("")[..]);
+    // 全角字符按 Shift-JIS 的字节参与异或，与 ASCII 密码去掉异或后的随机数一致
+    let pass = "でんしゃでＤ";
+    let (sjis, _, _) = SHIFT_JIS.encode(pass);
+    assert_eq!(sjis.len(), 12);
+    let table = create_key_table(pass);
+    let ascii = create_key_table_with_seed("PackPass", passkey_hash(pass));
+    for i in 0..table.len() {
+        assert_eq!(table[i] ^ sjis[i % 12], ascii[i] ^ b"PackPass"[i % 8]);
+    }
+    assert_eq!(
+        &table[0..16],
+        &[202, 87, 146, 159, 134, 192, 89, 190, 253, 89, 243, 193, 78, 157, 130, 193]
+    );
+}
+
+/// 生成密钥表用的梅森旋转算法（MT19937）
+///
+/// 引擎的实现基于有符号整数，右移时会复制符号位，因此与标准的 MT19937 输出不同。
+/// 这里统一使用 `u32` 运算，并由 `signed_shift` 决定是否模拟引擎的算术右移。
 #[derive(Debug, Clone)]
 struct KeyTableGenerator {
-    m_table: Vec<i32>,
-    m_pos: usize,
+    state: [u32; STATE_LENGTH],
+    pos: usize,
+    signed_shift: bool,
 }
 
 const STATE_LENGTH: usize = 624;
 const STATE_M: usize = 397;
-const MATRIX_A: i32 = -1727483681;
-const TEMPERING_MASK_B: i32 = -1658038656;
-const TEMPERING_MASK_C: i32 = -272236544;
-
-const MAG01: [i32; 2] = [0, MATRIX_A];
+const MATRIX_A: u32 = 0x9908B0DF;
+const UPPER_MASK: u32 = 0x80000000;
+const LOWER_MASK: u32 = 0x7FFFFFFF;
+const TEMPERING_MASK_B: u32 = 0x9D2C5680;
+const TEMPERING_MASK_C: u32 = 0xEFC60000;
 
 impl KeyTableGenerator {
-    pub fn new(seed: i32) -> Self {
-        let mut s = Self::default();
+    /// 与引擎一致的生成器
+    pub fn new(seed: u32) -> Self {
+        Self::with_shift(seed, true)
+    }
+
+    /// 标准的 MT19937 生成器
+    #[cfg(test)]
+    pub fn mt19937(seed: u32) -> Self {
+        Self::with_shift(seed, false)
+    }
+
+    fn with_shift(seed: u32, signed_shift: bool) -> Self {
+        let mut s = Self {
+            state: [0; STATE_LENGTH],
+            pos: STATE_LENGTH,
+            signed_shift,
+        };
         s.s_rand(seed);
         s
     }
 
-    pub fn s_rand(&mut self, seed: i32) {
-        self.m_table[0] = seed;
-        for i in 1..STATE_LENGTH as i32 {
-            let last = self.m_table[i as usize - 1];
-            let v = i + 0x6C078965i32.overflowing_mul(last ^ (last >> 30)).0;
-            self.m_table[i as usize] = v;
+    fn shr(&self, v: u32, n: u32) -> u32 {
+        if self.signed_shift {
+            ((v as i32) >> n) as u32
+        } else {
+            v >> n
         }
-        self.m_pos = STATE_LENGTH;
-    }
-
-    pub fn rand(&mut self) -> i32 {
-        if self.m_pos >= STATE_LENGTH {
-            let mut i = 0usize;
-            while i < STATE_LENGTH - STATE_M {
-                let mt0 = self.m_table[i];
-                let mt1 = self.m_table[i + 1];
-                let x = mt0 ^ mt1;
-                self.m_table[i] = self.m_table[i + STATE_M]
-                    ^ MAG01[((self.m_table[i] ^ x) & 1) as usize]
-                    ^ ((self.m_table[i] ^ x & 0x7FFFFFFF) >> 1);
-                i += 1;
-            }
-            while i < STATE_LENGTH - 1 {
-                let mt0 = self.m_table[i];
-                let mt1 = self.m_table[i + 1];
-                let x = mt0 ^ mt1;
-                self.m_table[i] = self.m_table[i + STATE_M - STATE_LENGTH]
-                    ^ MAG01[((self.m_table[i] ^ x) & 1) as usize]
-                    ^ ((self.m_table[i] ^ x & 0x7FFFFFFF) >> 1);
-                i += 1;
-            }
-            let z = self.m_table[STATE_LENGTH - 1]
-                ^ (self.m_table[0] ^ self.m_table[STATE_LENGTH - 1]) & 0x7FFFFFFF;
-            self.m_table[STATE_LENGTH - 1] =
-                self.m_table[STATE_M - 1] ^ (z >> 1) ^ MAG01[(z & 1) as usize];
-            self.m_pos = 0;
+    }
+
+    pub fn s_rand(&mut self, seed: u32) {
+        self.state[0] = seed;
+        for i in 1..STATE_LENGTH {
+            let last = self.state[i - 1];
+            self.state[i] = 0x6C078965u32
+                .wrapping_mul(last ^ self.shr(last, 30))
+                .wrapping_add(i as u32);
         }
-        let y = self.m_table[self.m_pos];
-        self.m_pos += 1;
-        let y = y ^ (y >> 11);
-        let y = y ^ (y << 7) & TEMPERING_MASK_B;
-        let y = y ^ (y << 15) & TEMPERING_MASK_C;
-        let y = y ^ (y >> 18);
-        y
+        self.pos = STATE_LENGTH;
     }
-}
 
-impl Default for KeyTableGenerator {
-    fn default() -> Self {
-        let mut m_table = Vec::with_capacity(STATE_LENGTH);
-        unsafe {
-            m_table.set_len(STATE_LENGTH);
+    fn twist(&mut self) {
+        for i in 0..STATE_LENGTH {
+            let y =
+                (self.state[i] & UPPER_MASK) | (self.state[(i + 1) % STATE_LENGTH] & LOWER_MASK);
+            let mag = if y & 1 == 0 { 0 } else { MATRIX_A };
+            self.state[i] = self.state[(i + STATE_M) % STATE_LENGTH] ^ self.shr(y, 1) ^ mag;
         }
-        m_table.fill(0);
-        Self { m_table, m_pos: 0 }
+        self.pos = 0;
+    }
+
+    pub fn rand(&mut self) -> u32 {
+        if self.pos >= STATE_LENGTH {
+            self.twist();
+        }
+        let y = self.state[self.pos];
+        self.pos += 1;
+        let y = y ^ self.shr(y, 11);
+        let y = y ^ ((y << 7) & TEMPERING_MASK_B);
+        let y = y ^ ((y << 15) & TEMPERING_MASK_C);
+        y ^ self.shr(y, 18)
+    }
+}
+
+#[test]
+fn test_mt19937_reference() {
+    // 标准 MT19937 以 5489 为种子的输出，第 10000 个输出见 C++ 标准 [rand.predef]
+    let mut rng = KeyTableGenerator::mt19937(5489);
+    let head: Vec<u32> = (0..5).map(|_| rng.rand()).collect();
+    assert_eq!(
+        head,
+        [3499211612, 581869302, 3890346734, 3586334585, 545404204]
+    );
+    let last = (5..10000).map(|_| rng.rand()).last();
+    assert_eq!(last, Some(4123659995));
+
+    let mut rng = KeyTableGenerator::mt19937(0);
+    let head: Vec<u32> = (0..3).map(|_| rng.rand()).collect();
+    assert_eq!(head, [2357136044, 2546248239, 3071714933]);
+}
+
+#[test]
+fn test_mt19937_engine_variant() {
+    let mut rng = KeyTableGenerator::new(5489);
+    let head: Vec<u32> = (0..5).map(|_| rng.rand()).collect();
+    assert_eq!(
+        head,
+        [31526908, 1465395198, 1404365412, 205606042, 1832222152]
+    );
+}
+
+#[test]
+fn test_key_table_snapshots() {
+    // (密码, 整表 crc32, 前 8 字节, 后 8 字节)
+    let cases: [(&str, u32, [u8; 8], [u8; 8]); 5] = [
+        (
+            "",
+            680403429,
+            [43, 153, 246, 46, 115, 3, 156, 205],
+            [83, 49, 125, 241, 26, 126, 92, 132],
+        ),
+        (
+            "PackPass",
+            680958917,
+            [31, 53, 87, 241, 209, 166, 84, 117],
+            [148, 102, 197, 65, 138, 201, 153, 151],
+        ),
+        (
+            "でんしゃでＤ",
+            2173610021,
+            [202, 87, 146, 159, 134, 192, 89, 190],
+            [42, 226, 72, 168, 147, 37, 142, 158],
+        ),
+        (
+            "DenshaDeD_Climax",
+            2178781373,
+            [0, 83, 81, 15, 172, 39, 86, 122],
+            [46, 56, 106, 235, 162, 116, 253, 87],
+        ),
+        (
+            "電車でＤ ClimaxStage",
+            552856878,
+            [206, 165, 125, 85, 78, 142, 89, 210],
+            [77, 209, 136, 114, 232, 109, 185, 254],
+        ),
+    ];
+    for (pass, crc, head, tail) in cases.iter() {
+        let table = create_key_table(pass);
+        assert_eq!(&table[..8], head, "head of {:?}", pass);
+        assert_eq!(&table[table.len() - 8..], tail, "tail of {:?}", pass);
+        assert_eq!(compute(&table, 0, table.len()), *crc, "crc32 of {:?}", pass);
     }
 }
@@ -0,0 +1,266 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 将条目直接写入 zip / tar 归档，或从归档读取条目用于打包，中间不经过文件系统
+//!
+//! 归档中使用 `/` 分隔路径，Pack 中的条目名使用 `\`。
+
+use anyhow::{Error, Result};
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::entry_path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Zip,
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// 按扩展名判断格式
+    pub fn from_path(path: &Path) -> Result<Self> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .parse()
+            .map_err(|_| {
+                Error::msg(format!(
+                    "Unknown archive format of {}, expects .zip or .tar",
+                    path.display()
+                ))
+            })
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "zip" => Ok(Self::Zip),
+            "tar" => Ok(Self::Tar),
+            _ => Err(Error::msg(format!("Unknown archive format: {}", s))),
+        }
+    }
+}
+
+/// 按 `entry_path` 去掉空的、`.` 与 `..` 部分，以免解压时写出目标目录
+fn archive_name(name: &str) -> String {
+    entry_path(name)
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn entry_name(name: &str) -> String {
+    name.trim_start_matches('/').replace('/', "\\")
+}
+
+/// 自 1970 年起的天数换算为年月日
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// zip 使用 DOS 时间，超出 1980..2107 的时间取默认值
+fn zip_time(secs: u64) -> zip::DateTime {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    zip::DateTime::from_date_and_time(
+        year as u16,
+        month as u8,
+        day as u8,
+        (time / 3600) as u8,
+        (time / 60 % 60) as u8,
+        (time % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+pub enum ArchiveWriter<W: Write + Seek> {
+    Zip(zip::ZipWriter<W>),
+    Tar(tar::Builder<W>),
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    pub fn new(format: ArchiveFormat, output: W) -> Self {
+        match format {
+            ArchiveFormat::Zip => Self::Zip(zip::ZipWriter::new(output)),
+            ArchiveFormat::Tar => Self::Tar(tar::Builder::new(output)),
+        }
+    }
+
+    /// 写入一个条目，`name` 为 Pack 中的条目名，`mtime` 通常取 Pack 文件的修改时间
+    pub fn add(&mut self, name: &str, data: &[u8], mtime: SystemTime) -> Result<()> {
+        self.add_with(name, data.len() as u64, mtime, |output| {
+            Ok(output.write_all(data)?)
+        })
+    }
+
+    /// 添加 `size` 字节的文件，数据由 `write` 逐块写入，不必整个读入内存
+    pub fn add_with(
+        &mut self,
+        name: &str,
+        size: u64,
+        mtime: SystemTime,
+        write: impl FnOnce(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        let name = archive_name(name);
+        let secs = mtime
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        match self {
+            Self::Zip(zip) => {
+                let options = zip::write::FileOptions::default()
+                    .compression_method(zip::CompressionMethod::Deflated)
+                    .last_modified_time(zip_time(secs))
+                    .large_file(size >= u32::MAX as u64);
+                zip.start_file(name, options)?;
+                write(zip)?;
+            }
+            Self::Tar(tar) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(size);
+                header.set_mode(0o644);
+                header.set_mtime(secs);
+                let mut entry = tar.append_writer(&mut header, name)?;
+                write(&mut entry)?;
+                entry.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<W> {
+        Ok(match self {
+            Self::Zip(mut zip) => zip.finish()?,
+            Self::Tar(tar) => tar.into_inner()?,
+        })
+    }
+}
+
+/// 归档中的一个文件
+pub struct ArchiveMember<'a> {
+    /// Pack 中的条目名
+    pub name: String,
+    pub size: u64,
+    /// 内容未压缩地存放在归档中时，内容在归档中的偏移，可以直接引用而不必读出
+    pub offset: Option<u64>,
+    pub reader: &'a mut dyn Read,
+}
+
+/// 依次读出归档中的文件交给 `f`，目录被跳过
+pub fn read_archive(
+    format: ArchiveFormat,
+    input: impl Read + Seek,
+    mut f: impl FnMut(ArchiveMember) -> Result<()>,
+) -> Result<()> {
+    match format {
+        ArchiveFormat::Zip => {
+            let mut zip = zip::ZipArchive::new(input)?;
+            for i in 0..zip.len() {
+                let mut file = zip.by_index(i)?;
+                if file.is_dir() {
+                    continue;
+                }
+                let offset = match file.compression() {
+                    zip::CompressionMethod::Stored => Some(file.data_start()),
+                    _ => None,
+                };
+                f(ArchiveMember {
+                    name: entry_name(file.name()),
+                    size: file.size(),
+                    offset,
+                    reader: &mut file,
+                })?;
+            }
+        }
+        ArchiveFormat::Tar => {
+            let mut tar = tar::Archive::new(input);
+            for file in tar.entries()? {
+                let mut file = file?;
+                if !file.header().entry_type().is_file() {
+                    continue;
+                }
+                f(ArchiveMember {
+                    name: entry_name(&file.path()?.to_string_lossy()),
+                    size: file.size(),
+                    offset: Some(file.raw_file_position()),
+                    reader: &mut file,
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_archive_round_trip() {
+    let entries = [("字幕\\001.FVT", &b"D3_FVT"[..]), ("a.png", b"\x89PNG")];
+    let mtime = UNIX_EPOCH + std::time::Duration::from_secs(1617235200);
+    assert_eq!(zip_time(1617235200).year(), 2021);
+    assert_eq!(civil_from_days(18718), (2021, 4, 1));
+    for &format in &[ArchiveFormat::Zip, ArchiveFormat::Tar] {
+        let mut writer = ArchiveWriter::new(format, std::io::Cursor::new(Vec::new()));
+        for (name, data) in &entries {
+            writer.add(name, data, mtime).unwrap();
+        }
+        let mut archive = writer.finish().unwrap();
+        archive.set_position(0);
+        let raw = archive.get_ref().clone();
+        let mut read = Vec::new();
+        read_archive(format, archive, |member| {
+            let mut data = Vec::new();
+            member.reader.read_to_end(&mut data)?;
+            // 未压缩的文件可以按偏移直接引用
+            if let Some(offset) = member.offset {
+                let offset = offset as usize;
+                assert_eq!(&raw[offset..offset + data.len()], &data[..]);
+            }
+            assert_eq!(member.size, data.len() as u64);
+            read.push((member.name, data));
+            Ok(())
+        })
+        .unwrap();
+        let expected: Vec<_> = entries
+            .iter()
+            .map(|(name, data)| (name.to_string(), data.to_vec()))
+            .collect();
+        assert_eq!(read, expected, "{:?}", format);
+    }
+    assert_eq!(
+        ArchiveFormat::from_path(Path::new("out.TAR")).unwrap(),
+        ArchiveFormat::Tar
+    );
+    assert!(ArchiveFormat::from_path(Path::new("out.7z")).is_err());
+    assert_eq!(archive_name("..\\..\\etc/./passwd"), "etc/passwd");
+    assert_eq!(archive_name("/abs\\a.png"), "abs/a.png");
+}
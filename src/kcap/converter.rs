@@ -0,0 +1,182 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 解包/打包时自动转换条目
+//!
+//! 每种格式实现 `Converter`，注册到 `Converters` 后由解包与打包流程按条目名与内容选用，
+//! 新的 Selene 格式只需添加一个实现。
+
+use anyhow::Result;
+use std::path::Path;
+
+use crate::fvt::{self, DataFormat, DecodeOptions, EncodeOptions, FvtVariant};
+
+pub trait Converter: Send + Sync {
+    /// 显示用的名称，如 `fvt`
+    fn name(&self) -> &'static str;
+
+    /// 解包时按条目名与解密后开头的字节判断是否由此转换
+    fn accepts_unpacked(&self, name: &str, head: &[u8]) -> bool;
+
+    /// 将条目数据转换为便于编辑的文件，返回新的条目名与数据
+    fn unpack(&self, name: &str, data: &[u8]) -> Result<(String, Vec<u8>)>;
+
+    /// 打包时按文件名与开头的字节判断是否由此转换，`head` 取开头 `sniff::SNIFF_LENGTH` 字节即可
+    fn accepts_packed(&self, name: &str, head: &[u8]) -> bool;
+
+    /// `unpack` 的逆转换，返回原来的条目名与数据
+    fn pack(&self, name: &str, data: &[u8]) -> Result<(String, Vec<u8>)>;
+}
+
+/// 已注册的转换器，按注册顺序选用第一个匹配的
+#[derive(Default)]
+pub struct Converters {
+    converters: Vec<Box<dyn Converter>>,
+}
+
+impl Converters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 包含所有内置转换器
+    pub fn builtin() -> Self {
+        Self::builtin_with(DecodeOptions::default(), EncodeOptions::default())
+    }
+
+    /// 同 `builtin`，FVT 按给定的选项转换，与 `fvt decode` / `fvt encode` 一致
+    pub fn builtin_with(decode: DecodeOptions, encode: EncodeOptions) -> Self {
+        let mut converters = Self::new();
+        converters.register(FvtConverter { decode, encode });
+        converters
+    }
+
+    pub fn register(&mut self, converter: impl Converter + 'static) {
+        self.converters.push(Box::new(converter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.converters.is_empty()
+    }
+
+    pub fn for_unpacked(&self, name: &str, head: &[u8]) -> Option<&dyn Converter> {
+        self.converters
+            .iter()
+            .find(|converter| converter.accepts_unpacked(name, head))
+            .map(Box::as_ref)
+    }
+
+    pub fn for_packed(&self, name: &str, head: &[u8]) -> Option<&dyn Converter> {
+        self.converters
+            .iter()
+            .find(|converter| converter.accepts_packed(name, head))
+            .map(Box::as_ref)
+    }
+}
+
+/// FVT 字幕与 `fvt decode` / `fvt encode` 所用的数据格式互转，文件名追加格式扩展名，如 `001.FVT.json`
+///
+/// 解包时按魔数识别，打包时按开头声明的 `tag` 识别，条目名不以 `.FVT` 结尾也能还原。
+#[derive(Default)]
+pub struct FvtConverter {
+    pub decode: DecodeOptions,
+    pub encode: EncodeOptions,
+}
+
+impl Converter for FvtConverter {
+    fn name(&self) -> &'static str {
+        "fvt"
+    }
+
+    fn accepts_unpacked(&self, _name: &str, head: &[u8]) -> bool {
+        matches!(fvt::detect(head), fvt::Detection::Known(_))
+    }
+
+    fn unpack(&self, name: &str, data: &[u8]) -> Result<(String, Vec<u8>)> {
+        let mut output = Vec::new();
+        fvt::decode_with(&mut &data[..], &mut output, &self.decode)?;
+        Ok((
+            format!("{}.{}", name, self.decode.format.extension()),
+            output,
+        ))
+    }
+
+    fn accepts_packed(&self, name: &str, head: &[u8]) -> bool {
+        let path = Path::new(name);
+        if DataFormat::from_path(path).is_none() {
+            return false;
+        }
+        // tag 写在最前面，编辑时弄坏了 tag 的 `*.FVT.json` 也按文件名交给编码报错
+        let tagged = FvtVariant::ALL.iter().any(|variant| {
+            head.windows(variant.magic().len())
+                .any(|window| window == variant.magic())
+        });
+        tagged
+            || path
+                .file_stem()
+                .and_then(|stem| Path::new(stem).extension())
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("fvt"))
+    }
+
+    fn pack(&self, name: &str, data: &[u8]) -> Result<(String, Vec<u8>)> {
+        let path = Path::new(name);
+        let options = EncodeOptions {
+            format: DataFormat::from_path(path).unwrap_or_default(),
+            ..self.encode.clone()
+        };
+        let mut output = Vec::new();
+        fvt::encode_with(&mut &data[..], &mut output, &options)?;
+        Ok((
+            path.with_extension("").to_string_lossy().into_owned(),
+            output,
+        ))
+    }
+}
+
+#[test]
+fn test_fvt_converter() {
+    let converters = Converters::builtin();
+    let data = fvt::sample(
+        fvt::FvtVariant::ClimaxRising.magic(),
+        &[b"\x93\x64\x8E\xD4"],
+    );
+    let converter = converters.for_unpacked("字幕\\001.FVT", &data).unwrap();
+    assert_eq!(converter.name(), "fvt");
+    assert!(converters.for_unpacked("a.png", b"\x89PNG").is_none());
+
+    let (name, json) = converter.unpack("字幕\\001.FVT", &data).unwrap();
+    assert_eq!(name, "字幕\\001.FVT.json");
+    let converter = converters.for_packed(&name, &json).unwrap();
+    assert_eq!(
+        converter.pack(&name, &json).unwrap(),
+        ("字幕\\001.FVT".into(), data.clone())
+    );
+    assert!(converters.for_packed("config.json", b"{}").is_none());
+    assert!(converters.for_packed("001.FVT", &data).is_none());
+
+    // 条目名不以 .FVT 结尾时按内容识别，同样能还原
+    let converter = converters.for_unpacked("字幕\\001.bin", &data).unwrap();
+    let (name, json) = converter.unpack("字幕\\001.bin", &data).unwrap();
+    assert_eq!(name, "字幕\\001.bin.json");
+    let head = &json[..json.len().min(crate::sniff::SNIFF_LENGTH)];
+    let converter = converters.for_packed(&name, head).unwrap();
+    assert_eq!(
+        converter.pack(&name, &json).unwrap(),
+        ("字幕\\001.bin".into(), data)
+    );
+}
@@ -0,0 +1,29 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 电车でＤ 系列（Selene 引擎）游戏数据的解包与编码工具
+
+pub mod crc32;
+pub mod fvt;
+pub mod kcap;
+#[cfg(target_os = "linux")]
+pub mod mount;
+pub mod serve;
+pub mod sniff;
+pub mod text;
+pub mod tui;
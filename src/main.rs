@@ -16,27 +16,145 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 //
 
-mod crc32;
-mod fvt;
-mod kcap;
-
 use std::fs::OpenOptions;
-use std::path::Path;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use clap::clap_app;
+use clap::{clap_app, ArgMatches};
 
 use anyhow::{Error, Result};
 
+use denshaded_tools::fvt::{
+    self,
+    catalog::CatalogFormat,
+    convert::ConvertDefaults,
+    subtitle::{SubtitleFormat, TimingMap},
+    DataFormat, DecodeOptions, Detection, EncodeOptions, Fvt, FvtVariant,
+};
+use denshaded_tools::kcap::{
+    self,
+    archive::{self, ArchiveFormat, ArchiveWriter},
+    converter::Converters,
+    KCAPPackReader, KCAPPackWriter, KeyTable,
+};
+use denshaded_tools::sniff::{self, FileKind};
+use denshaded_tools::text::{charmap::CharMap, TextEncoding};
+
+/// 根据命令行参数取得密钥表，优先级为密钥表文件、种子、密码
+fn key_table_of(matches: &ArgMatches) -> Result<Arc<KeyTable>> {
+    let pass = matches.value_of("PASS").unwrap_or("PackPass");
+    if let Some(key_table) = matches.value_of("KEYTABLE") {
+        Ok(Arc::new(kcap::load_key_table(key_table)?))
+    } else if let Some(seed) = matches.value_of("SEED") {
+        let seed = if let Some(hex) = seed.strip_prefix("0x") {
+            u32::from_str_radix(hex, 16)
+        } else {
+            seed.parse()
+        }
+        .map_err(|_| Error::msg(format!("Invalid seed: {}", seed)))?;
+        Ok(Arc::new(kcap::create_key_table_with_seed(pass, seed)))
+    } else {
+        Ok(kcap::cached_key_table(pass))
+    }
+}
+
+fn encoding_of(matches: &ArgMatches) -> Result<TextEncoding> {
+    matches.value_of("ENCODING").unwrap_or("shift-jis").parse()
+}
+
+fn charmap_of(matches: &ArgMatches) -> Result<Option<Arc<CharMap>>> {
+    matches
+        .value_of("CHARMAP")
+        .map(|path| Ok(Arc::new(CharMap::load(Path::new(path))?)))
+        .transpose()
+}
+
+fn decode_options_of(matches: &ArgMatches) -> Result<DecodeOptions> {
+    Ok(DecodeOptions {
+        encoding: encoding_of(matches)?,
+        format: matches.value_of("DATA_FORMAT").unwrap_or("json").parse()?,
+        charmap: charmap_of(matches)?,
+    })
+}
 
-use kcap::{KCAPPackReader, KCAPPackWriter};
+/// 编码选项，`encoding` 为覆盖 json 中记录的编码所用的参数名，输入格式由调用者按扩展名设置
+fn encode_options_of(matches: &ArgMatches, encoding: &str) -> Result<EncodeOptions> {
+    Ok(EncodeOptions {
+        format: DataFormat::Json,
+        overflow: matches.value_of("OVERFLOW").unwrap_or("error").parse()?,
+        strict: matches.is_present("STRICT"),
+        encoding: matches.value_of(encoding).map(str::parse).transpose()?,
+        charmap: charmap_of(matches)?,
+    })
+}
 
-fn unpack(file: &Path, save_dir: &Path, pass: &str) -> Result<()> {
+/// 解包到目录，`to_archive` 时 `save_dir` 为 zip / tar 归档的路径
+fn unpack(
+    file: &Path,
+    save_dir: &Path,
+    to_archive: bool,
+    key_table: Arc<KeyTable>,
+    encoding: TextEncoding,
+    kinds: &[FileKind],
+    converters: &Converters,
+) -> Result<()> {
     println!("Unpack {}", file.display());
     println!("    to {}", save_dir.display());
-    let mut pack = KCAPPackReader::new(file, pass)?;
+    let mut pack = KCAPPackReader::with_encoding(file, key_table, encoding)?;
+    let mut archive = if to_archive {
+        let format = ArchiveFormat::from_path(save_dir)?;
+        Some(ArchiveWriter::new(format, std::fs::File::create(save_dir)?))
+    } else {
+        None
+    };
+    let mtime = pack.file.metadata()?.modified()?;
     for i in 0..pack.entries.len() {
+        let head = pack.read_head(i, sniff::SNIFF_LENGTH)?;
+        if !kinds.is_empty() && !kinds.contains(&sniff::sniff(&head).kind) {
+            continue;
+        }
         let name = pack.entries[i].name.clone();
-        let save_file = save_dir.join(&name);
+        // 转换失败时保留原始数据
+        let converted = match converters.for_unpacked(&name, &head) {
+            Some(converter) => {
+                let mut data = Vec::with_capacity(pack.entries[i].size);
+                pack.read_to(i, &mut data)?;
+                match converter.unpack(&name, &data) {
+                    Ok(converted) => Some(converted),
+                    Err(err) => {
+                        eprintln!(
+                            "WARN: Can't convert {} by {}: {}",
+                            name,
+                            converter.name(),
+                            err
+                        );
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        if let Some(archive) = &mut archive {
+            match converted {
+                Some((save_name, data)) => {
+                    println!("Exacting {} -> {}", &name, save_name);
+                    archive.add(&save_name, &data, mtime)?;
+                }
+                None => {
+                    println!("Exacting {} -> {}", &name, name);
+                    let size = pack.entries[i].size as u64;
+                    archive.add_with(&name, size, mtime, |mut output| {
+                        pack.read_to(i, &mut output)
+                    })?;
+                }
+            }
+            continue;
+        }
+        let save_file = match &converted {
+            Some((name, _)) => save_dir.join(kcap::entry_path(name)),
+            None => save_dir.join(kcap::entry_path(&name)),
+        };
         let save_dir = save_file.parent().unwrap();
         std::fs::create_dir_all(save_dir).unwrap_or_default();
         println!("Exacting {} -> {}", &name, save_file.display());
@@ -45,24 +163,238 @@ fn unpack(file: &Path, save_dir: &Path, pass: &str) -> Result<()> {
             .truncate(true)
             .write(true)
             .open(save_file)?;
-        pack.read_to(i, &mut save_file)?;
+        match converted {
+            Some((_, data)) => save_file.write_all(&data)?,
+            None => pack.read_to(i, &mut save_file)?,
+        }
+    }
+    if let Some(archive) = archive {
+        archive.finish()?;
+    }
+    Ok(())
+}
+
+/// `--convert` 时使用内置的转换器，FVT 文本按 `--encoding` 与 `--charmap` 转换
+fn converters_of(matches: &ArgMatches) -> Result<Converters> {
+    if !matches.is_present("CONVERT") {
+        return Ok(Converters::new());
+    }
+    let charmap = charmap_of(matches)?;
+    let decode = DecodeOptions {
+        encoding: encoding_of(matches)?,
+        charmap: charmap.clone(),
+        ..Default::default()
+    };
+    let encode = EncodeOptions {
+        charmap,
+        ..Default::default()
+    };
+    Ok(Converters::builtin_with(decode, encode))
+}
+
+#[cfg(all(target_os = "linux", feature = "mount"))]
+fn mount(matches: &ArgMatches) -> Result<()> {
+    use denshaded_tools::mount::{MountOptions, WriteBack};
+
+    let file = Path::new(matches.value_of("INPUT").expect("Input is not provided"));
+    let mountpoint = Path::new(
+        matches
+            .value_of("MOUNTPOINT")
+            .expect("Mount point is not provided"),
+    );
+    let encoding = encoding_of(matches)?;
+    let write_back = if let Some(patch) = matches.value_of("PATCH") {
+        Some(WriteBack::Patch(patch.into()))
+    } else if let Some(output) = matches.value_of("OUTPUT") {
+        Some(WriteBack::Rebuild(output.into()))
+    } else if matches.is_present("WRITABLE") {
+        Some(WriteBack::Rebuild(file.into()))
+    } else {
+        None
+    };
+    let max_file_size = match matches.value_of("MAX_FILE_SIZE") {
+        Some(size) => Some(
+            size.parse::<u64>()
+                .ok()
+                .and_then(|size| size.checked_mul(1 << 20))
+                .ok_or_else(|| Error::msg(format!("Invalid file size: {}", size)))?,
+        ),
+        None => None,
+    };
+    let options = MountOptions {
+        allow_other: matches.is_present("ALLOW_OTHER"),
+        write_back,
+        encoding,
+        max_file_size,
+    };
+    let pack = KCAPPackReader::with_encoding(file, key_table_of(matches)?, encoding)?;
+    println!("Mount {}", file.display());
+    println!("   at {}", mountpoint.display());
+    println!(
+        "Press Ctrl-C or run \"fusermount3 -u {}\" to stop",
+        mountpoint.display()
+    );
+    let written = denshaded_tools::mount::mount(pack, mountpoint, &options)?;
+    match (&options.write_back, written) {
+        (Some(WriteBack::Rebuild(output)), Some(count)) => {
+            println!("Rebuilt {} with {} entries", output.display(), count)
+        }
+        (Some(WriteBack::Patch(output)), Some(count)) => {
+            println!("Wrote {} changed entries to {}", count, output.display())
+        }
+        (Some(_), None) => println!("Nothing changed"),
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", feature = "mount")))]
+fn mount(_: &ArgMatches) -> Result<()> {
+    Err(Error::msg(
+        "Mounting is only supported on Linux with the mount feature",
+    ))
+}
+
+fn serve(
+    file: &Path,
+    key_table: Arc<KeyTable>,
+    encoding: TextEncoding,
+    charmap: Option<Arc<CharMap>>,
+    port: u16,
+) -> Result<()> {
+    let pack = KCAPPackReader::with_encoding(file, key_table, encoding)?;
+    let title = file.file_name().unwrap_or_default().to_string_lossy();
+    let server = denshaded_tools::serve::Server::new(pack, encoding, charmap, &title)?;
+    println!("Serving {} at http://127.0.0.1:{}/", file.display(), port);
+    server.run(port)
+}
+
+fn tui(
+    file: &Path,
+    output: &Path,
+    key_table: Arc<KeyTable>,
+    encoding: TextEncoding,
+    charmap: Option<Arc<CharMap>>,
+) -> Result<()> {
+    let pack = KCAPPackReader::with_encoding(file, key_table, encoding)?;
+    denshaded_tools::tui::run(file, pack, encoding, charmap, output)
+}
+
+/// 列出 Pack 中的条目及按内容识别的类型，`kinds` 非空时只列出这些类型
+fn list(
+    file: &Path,
+    key_table: Arc<KeyTable>,
+    encoding: TextEncoding,
+    kinds: &[FileKind],
+) -> Result<()> {
+    let pack = KCAPPackReader::with_encoding(file, key_table, encoding)?;
+    for (i, entry) in pack.entries.iter().enumerate() {
+        let file_type = pack.sniff(i)?;
+        if !kinds.is_empty() && !kinds.contains(&file_type.kind) {
+            continue;
+        }
+        println!(
+            "{:>5} {:>10} {} {:<18} {}",
+            i,
+            entry.size,
+            if entry.encrypted { "E" } else { "-" },
+            file_type.to_string(),
+            entry.name
+        );
     }
     Ok(())
 }
 
-fn pack(dir: &Path, save_file: &Path, pass: &str) -> Result<()> {
+fn kinds_of(matches: &ArgMatches) -> Result<Vec<FileKind>> {
+    matches
+        .values_of("TYPE")
+        .map(|kinds| kinds.map(str::parse).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))
+}
+
+/// 打包目录，`from_archive` 时 `dir` 为 zip / tar 归档的路径
+fn pack(
+    dir: &Path,
+    from_archive: bool,
+    save_file: &Path,
+    key_table: Arc<KeyTable>,
+    encoding: TextEncoding,
+    converters: &Converters,
+) -> Result<()> {
     println!("Pack {}", dir.display());
     println!("  to {}", save_file.display());
-    let dir_string = dir.to_string_lossy().to_string();
-    let mut pack = KCAPPackWriter::new(Some(pass.into()));
-    for entry in walkdir::WalkDir::new(dir) {
-        let entry = entry?;
-        if entry.file_type().is_file() {
-            let path = entry.path();
-            let name = path.clone().to_path_buf().to_string_lossy().to_string();
-            let name = name.trim_start_matches(&format!("{}\\", dir_string));
-            println!("Packing {} -> {}", path.display(), name);
-            pack.add_entry(path, name.into())?;
+    let mut pack = KCAPPackWriter::with_key_table(Some(key_table));
+    pack.encoding = encoding;
+    if from_archive {
+        let format = ArchiveFormat::from_path(dir)?;
+        // 未压缩的文件按偏移直接引用归档中的数据，只有需要解压或转换的文件读入内存
+        let file = Arc::new(std::fs::File::open(dir)?);
+        archive::read_archive(format, &*file, |member| {
+            let name = member.name;
+            let mut head = Vec::new();
+            (&mut *member.reader)
+                .take(sniff::SNIFF_LENGTH as u64)
+                .read_to_end(&mut head)?;
+            let reader = &mut head.as_slice().chain(member.reader);
+            match (converters.for_packed(&name, &head), member.offset) {
+                (Some(converter), _) => {
+                    let mut data = Vec::new();
+                    reader.read_to_end(&mut data)?;
+                    let (packed_name, data) = converter
+                        .pack(&name, &data)
+                        .map_err(|err| Error::msg(format!("{}: {}", name, err)))?;
+                    println!("Packing {} -> {} ({})", name, packed_name, converter.name());
+                    pack.add_data(data, &packed_name);
+                }
+                (None, Some(offset)) => {
+                    println!("Packing {}", name);
+                    pack.add_file_range(file.clone(), offset, member.size, &name);
+                }
+                (None, None) => {
+                    println!("Packing {}", name);
+                    let mut data = Vec::with_capacity(member.size as usize);
+                    reader.read_to_end(&mut data)?;
+                    pack.add_data(data, &name);
+                }
+            }
+            Ok(())
+        })?;
+    } else {
+        for entry in walkdir::WalkDir::new(dir) {
+            let entry = entry?;
+            if entry.file_type().is_file() {
+                let path = entry.path();
+                // Pack 中的条目名相对于目录，以 `\` 分隔
+                let name = path
+                    .strip_prefix(dir)?
+                    .iter()
+                    .map(|part| part.to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("\\");
+                let name = name.as_str();
+                let mut head = Vec::new();
+                std::fs::File::open(path)?
+                    .take(sniff::SNIFF_LENGTH as u64)
+                    .read_to_end(&mut head)?;
+                match converters.for_packed(name, &head) {
+                    Some(converter) => {
+                        let (name, data) = converter
+                            .pack(name, &std::fs::read(path)?)
+                            .map_err(|err| Error::msg(format!("{}: {}", path.display(), err)))?;
+                        println!(
+                            "Packing {} -> {} ({})",
+                            path.display(),
+                            name,
+                            converter.name()
+                        );
+                        pack.add_data(data, &name);
+                    }
+                    None => {
+                        println!("Packing {} -> {}", path.display(), name);
+                        pack.add_entry(path, name)?;
+                    }
+                }
+            }
         }
     }
     println!("Writing {} -> {}", dir.display(), save_file.display());
@@ -75,7 +407,13 @@ fn pack(dir: &Path, save_file: &Path, pass: &str) -> Result<()> {
     Ok(())
 }
 
-fn fvt_decode(from: &Path, to: &Path) -> Result<()> {
+fn keytable_dump(save_file: &Path, key_table: Arc<KeyTable>) -> Result<()> {
+    println!("Dump key table to {}", save_file.display());
+    std::fs::write(save_file, &key_table[..])?;
+    Ok(())
+}
+
+fn fvt_decode(from: &Path, to: &Path, options: &DecodeOptions) -> Result<()> {
     println!("Decode from {}", from.display());
     println!("         to {}", to.display());
     let mut from = OpenOptions::new().read(true).open(from)?;
@@ -84,20 +422,243 @@ fn fvt_decode(from: &Path, to: &Path) -> Result<()> {
         .truncate(true)
         .write(true)
         .open(to)?;
-    fvt::decode(&mut from, &mut to)?;
+    fvt::decode_with(&mut from, &mut to, options)?;
     Ok(())
 }
 
-fn fvt_encode(from: &Path, to: &Path) -> Result<()> {
+fn fvt_encode(from: &Path, to: &Path, options: &EncodeOptions) -> Result<()> {
     println!("Encode from {}", from.display());
     println!("         to {}", to.display());
-    let mut from = OpenOptions::new().read(true).open(from)?;
-    let mut to = OpenOptions::new()
-        .create(true)
-        .truncate(true)
-        .write(true)
-        .open(to)?;
-    fvt::encode(&mut from, &mut to)?;
+    let options = &EncodeOptions {
+        format: DataFormat::from_path(from).unwrap_or_default(),
+        ..options.clone()
+    };
+    let mut fvt = Fvt::from_data(&mut &std::fs::read(from)?[..], options)
+        .map_err(|error| Error::msg(format!("{}: {}", from.display(), error)))?;
+    fvt.prepare(options);
+    report_unencodable(&fvt);
+    let mut data = Vec::new();
+    fvt.write_with(&mut data, options.strict)
+        .map_err(|error| Error::msg(format!("{}: {}", from.display(), error)))?;
+    std::fs::write(to, data)?;
+    Ok(())
+}
+
+/// 报告应用字符映射表之后仍无法编码的字符
+fn report_unencodable(fvt: &Fvt) {
+    let chars = fvt.unencodable();
+    if chars.is_empty() {
+        return;
+    }
+    println!(
+        "{} characters can't be encoded in {}:",
+        chars.len(),
+        fvt.encoding
+    );
+    for (c, count) in chars {
+        println!("    {} (U+{:04X}) x{}", c, c as u32, count);
+    }
+}
+
+fn fvt_decode_dir(from: &Path, to: &Path, options: &DecodeOptions) -> Result<()> {
+    println!("Decode from {}", from.display());
+    println!("         to {}", to.display());
+    print!("{}", fvt::batch::decode_dir(from, to, options)?);
+    Ok(())
+}
+
+fn fvt_decode_pack(
+    from: &Path,
+    to: &Path,
+    key_table: Arc<KeyTable>,
+    options: &DecodeOptions,
+) -> Result<()> {
+    println!("Decode from {}", from.display());
+    println!("         to {}", to.display());
+    let mut pack = KCAPPackReader::with_key_table(from, key_table)?;
+    print!("{}", fvt::batch::decode_pack(&mut pack, to, options)?);
+    Ok(())
+}
+
+fn fvt_encode_dir(from: &Path, to: &Path, options: &EncodeOptions) -> Result<()> {
+    println!("Encode from {}", from.display());
+    println!("         to {}", to.display());
+    print!("{}", fvt::batch::encode_dir(from, to, options)?);
+    Ok(())
+}
+
+/// 取得翻译目录格式，未指定时根据扩展名判断
+fn catalog_format_of(matches: &ArgMatches, path: &Path) -> Result<CatalogFormat> {
+    match matches.value_of("FORMAT") {
+        Some(format) => format.parse(),
+        None => path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .ok_or_else(|| Error::msg("Can't detect catalog format, use --format"))?
+            .parse(),
+    }
+}
+
+fn fvt_catalog_export(
+    from: &Path,
+    to: &Path,
+    format: CatalogFormat,
+    key_table: Option<Arc<KeyTable>>,
+    encoding: TextEncoding,
+) -> Result<()> {
+    println!("Collect from {}", from.display());
+    println!("          to {}", to.display());
+    let (entries, report) = match key_table {
+        Some(key_table) => fvt::catalog::collect_pack(
+            &mut KCAPPackReader::with_key_table(from, key_table)?,
+            encoding,
+        )?,
+        None => fvt::catalog::collect_dir(from, encoding)?,
+    };
+    std::fs::write(to, fvt::catalog::write_catalog(&entries, format)?)?;
+    println!("{} texts collected", entries.len());
+    print!("{}", report);
+    Ok(())
+}
+
+fn fvt_catalog_import(
+    catalog: &Path,
+    from: &Path,
+    to: &Path,
+    format: CatalogFormat,
+    encoding: TextEncoding,
+    options: &EncodeOptions,
+) -> Result<()> {
+    println!("Import {}", catalog.display());
+    println!("  from {}", from.display());
+    println!("    to {}", to.display());
+    let entries = fvt::catalog::read_catalog(&std::fs::read(catalog)?, format)?;
+    print!(
+        "{}",
+        fvt::catalog::apply_dir(from, to, &entries, encoding, options)?
+    );
+    Ok(())
+}
+
+fn fvt_info(file: &Path, encoding: TextEncoding) -> Result<()> {
+    let data = std::fs::read(file)?;
+    println!("{}", file.display());
+    println!("Size: {} bytes", data.len());
+    let detection = fvt::detect(&data);
+    print!("{}", detection);
+    if let Detection::Known(_) = detection {
+        match Fvt::from_read_with(&mut &data[..], encoding) {
+            Ok(fvt) => println!("Records: {}", fvt.records.len()),
+            Err(error) => println!("Error: {}", error),
+        }
+    }
+    Ok(())
+}
+
+/// 输入按标识识别为 FVT，否则按扩展名识别文本表示，输出扩展名为 json、toml 或 yaml 时写出文本表示
+fn fvt_convert(
+    from: &Path,
+    to: &Path,
+    variant: FvtVariant,
+    defaults: &ConvertDefaults,
+) -> Result<()> {
+    println!("Convert from {}", from.display());
+    println!("          to {} ({})", to.display(), variant.tag());
+    let data = std::fs::read(from)?;
+    let mut fvt: Fvt = if fvt::has_magic(&data) {
+        Fvt::from_read(&mut &data[..])?
+    } else {
+        let format = DataFormat::from_path(from).unwrap_or_default();
+        serde_json::from_value(format.to_value(&data)?)?
+    };
+    for warning in fvt.convert(variant, defaults)? {
+        println!("WARN: {}", warning);
+    }
+    let data = match DataFormat::from_path(to) {
+        Some(format) => format.to_vec(&fvt)?,
+        None => {
+            let mut data = Vec::new();
+            fvt.write_to(&mut data)?;
+            data
+        }
+    };
+    std::fs::write(to, data)?;
+    Ok(())
+}
+
+fn fvt_analyze(dir: &Path, encoding: TextEncoding) -> Result<()> {
+    println!("Analyze {}", dir.display());
+    let analysis = fvt::analyze::analyze_dir(dir, encoding)?;
+    print!("{}", analysis);
+    Ok(())
+}
+
+/// 与输入文件同目录、同名但扩展名不同的路径
+fn sibling_path(input: &Path, extension: &str) -> Result<PathBuf> {
+    let name = input
+        .file_stem()
+        .ok_or_else(|| Error::msg("Can't get name of input file"))?;
+    Ok(input.with_file_name(name).with_extension(extension))
+}
+
+/// 字幕的旁车文件，即在字幕文件名后追加 `.json`
+fn sidecar_path(subtitle: &Path) -> PathBuf {
+    let mut name = subtitle.as_os_str().to_owned();
+    name.push(".json");
+    PathBuf::from(name)
+}
+
+fn timing_of(matches: &ArgMatches) -> Result<TimingMap> {
+    Ok(TimingMap {
+        start: fvt::subtitle::parse_field(matches.value_of("START").unwrap_or("0"))?,
+        end: matches
+            .value_of("END")
+            .map(fvt::subtitle::parse_field)
+            .transpose()?,
+    })
+}
+
+fn fvt_export(
+    from: &Path,
+    to: &Path,
+    format: SubtitleFormat,
+    timing: &TimingMap,
+    options: &DecodeOptions,
+) -> Result<()> {
+    let sidecar = sidecar_path(to);
+    println!("Export from {}", from.display());
+    println!("         to {}", to.display());
+    println!("    sidecar {}", sidecar.display());
+    let mut input = OpenOptions::new().read(true).open(from)?;
+    let mut fvt = Fvt::from_read_with(&mut input, options.encoding)?;
+    if let Some(charmap) = &options.charmap {
+        fvt.map_decoded(charmap);
+    }
+    std::fs::write(to, fvt::subtitle::export(&fvt, format, timing))?;
+    std::fs::write(&sidecar, serde_json::to_string_pretty(&fvt)?)?;
+    Ok(())
+}
+
+fn fvt_import(
+    from: &Path,
+    sidecar: &Path,
+    to: &Path,
+    format: SubtitleFormat,
+    timing: &TimingMap,
+    options: &EncodeOptions,
+) -> Result<()> {
+    println!("Import from {}", from.display());
+    println!("    sidecar {}", sidecar.display());
+    println!("         to {}", to.display());
+    let mut fvt: Fvt = serde_json::from_slice(&std::fs::read(sidecar)?)?;
+    let subtitle = String::from_utf8(std::fs::read(from)?)?;
+    fvt::subtitle::import(&mut fvt, &subtitle, format, timing)?;
+    fvt.prepare(options);
+    report_unencodable(&fvt);
+    let mut data = Vec::new();
+    fvt.write_with(&mut data, options.strict)
+        .map_err(|error| Error::msg(format!("{}: {}", from.display(), error)))?;
+    std::fs::write(to, data)?;
     Ok(())
 }
 
@@ -112,34 +673,211 @@ fn main() -> Result<()> {
             (version: "1.0")
             (author: "SteveXMH <stevexmh@qq.com>")
             (@arg INPUT: +required "Sets the input file to use")
-            (@arg OUTPUT: -o --output "Set output directory path, defaults s \"[INPUT_DIR]/unpacked/[INPUT_NAME]\"")
-            (@arg PASS: -p --pass "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg OUTPUT: -o --output +takes_value "Set output directory path, defaults s \"[INPUT_DIR]/unpacked/[INPUT_NAME]\"")
+            (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg KEYTABLE: -k --keytable +takes_value conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+            (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            (@arg ENCODING: -e --encoding +takes_value "Encoding of entry names and of FVT text converted by --convert: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            (@arg TYPE: -t --type +takes_value +multiple number_of_values(1) "Only extract entries of this kind: image, audio, video, fvt, script, archive or unknown")
+            (@arg CONVERT: -c --convert "Convert known formats into editable files, e.g. FVT subtitles into json")
+            (@arg CHARMAP: --charmap +takes_value requires[CONVERT] "TSV file mapping characters to codes, applied after decoding converted FVT text")
+            (@arg TO_ARCHIVE: -a --("to-archive") +takes_value conflicts_with[OUTPUT] "Write entries into a .zip or .tar archive instead of a directory")
+        )
+        (@subcommand mount =>
+            (about: "Mount the game pack as a directory, needs Linux FUSE and fusermount3")
+            (version: "1.0")
+            (author: "SteveXMH <stevexmh@qq.com>")
+            (@arg INPUT: +required "Sets the input file to use")
+            (@arg MOUNTPOINT: +required "Sets the directory to mount at")
+            (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg KEYTABLE: -k --keytable +takes_value conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+            (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            (@arg ENCODING: -e --encoding +takes_value "Encoding of entry names: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            (@arg ALLOW_OTHER: --("allow-other") "Allow other users to access the mounted directory")
+            (@arg WRITABLE: -w --writable "Keep edits in memory and rebuild the pack after unmounting")
+            (@arg OUTPUT: -o --output +takes_value requires[WRITABLE] conflicts_with[PATCH] "Rebuild into this file instead of the mounted pack")
+            (@arg PATCH: --patch +takes_value requires[WRITABLE] "Only write changed and new files into this pack file")
+            (@arg MAX_FILE_SIZE: --("max-file-size") +takes_value requires[WRITABLE] "Largest file in MiB that can be written, edited files stay in memory until unmounting, defaults is 64")
+        )
+        (@subcommand serve =>
+            (about: "Browse entries of the game pack in a web browser on localhost")
+            (version: "1.0")
+            (author: "SteveXMH <stevexmh@qq.com>")
+            (@arg INPUT: +required "Sets the input file to use")
+            (@arg PORT: --port +takes_value "Port to listen on, defaults is 8080")
+            (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg KEYTABLE: -k --keytable +takes_value conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+            (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            (@arg ENCODING: -e --encoding +takes_value "Encoding of entry names and FVT text: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            (@arg CHARMAP: --charmap +takes_value "TSV file mapping characters to codes, applied to FVT text after decoding")
+        )
+        (@subcommand tui =>
+            (about: "Browse, extract and replace entries of the game pack in the terminal")
+            (version: "1.0")
+            (author: "SteveXMH <stevexmh@qq.com>")
+            (@arg INPUT: +required "Sets the input file to use")
+            (@arg OUTPUT: -o --output +takes_value "Set directory to extract entries to, defaults is \"[INPUT_DIR]/[INPUT_NAME]\"")
+            (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg KEYTABLE: -k --keytable +takes_value conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+            (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            (@arg ENCODING: -e --encoding +takes_value "Encoding of entry names and previewed text: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            (@arg CHARMAP: --charmap +takes_value "TSV file mapping characters to codes, applied to previewed text after decoding")
+        )
+        (@subcommand list =>
+            (about: "List entries of the game pack with their file types")
+            (version: "1.0")
+            (author: "SteveXMH <stevexmh@qq.com>")
+            (@arg INPUT: +required "Sets the input file to use")
+            (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg KEYTABLE: -k --keytable +takes_value conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+            (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            (@arg ENCODING: -e --encoding +takes_value "Encoding of entry names: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            (@arg TYPE: -t --type +takes_value +multiple number_of_values(1) "Only list entries of this kind: image, audio, video, fvt, script, archive or unknown")
         )
         (@subcommand pack =>
             (about: "Pack everything inside a directory to a Pack file (Still work in progress)")
             (version: "1.0")
             (author: "SteveXMH <stevexmh@qq.com>")
             (@arg INPUT: +required "Sets the input directory to use")
-            (@arg OUTPUT: -o --output "Set output file path, defaults s the same path and the same name of the directory")
-            (@arg PASS: -p --pass "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg OUTPUT: -o --output +takes_value "Set output file path, defaults s the same path and the same name of the directory")
+            (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+            (@arg KEYTABLE: -k --keytable +takes_value conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+            (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            (@arg ENCODING: -e --encoding +takes_value "Encoding of entry names: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            (@arg CONVERT: -c --convert "Convert files produced by \"unpack --convert\" back, e.g. json files declaring an FVT tag into FVT subtitles")
+            (@arg CHARMAP: --charmap +takes_value requires[CONVERT] "TSV file mapping characters to codes, applied before encoding converted FVT text")
+            (@arg FROM_ARCHIVE: -a --("from-archive") "Read entries from the .zip or .tar archive given as INPUT instead of a directory")
+        )
+        (@subcommand keytable =>
+            (about: "Subcommand for key tables")
+            (version: "1.0")
+            (author: "SteveXMH <stevexmh@qq.com>")
+            (@subcommand dump =>
+                (about: "Write the key table of a password to a file")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg OUTPUT: +required "Set output file path")
+                (@arg PASS: -p --pass +takes_value "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+                (@arg SEED: -s --seed +takes_value "Use a numeric seed instead of the hash of the password")
+            )
         )
         (@subcommand fvt =>
             (about: "Subcommand for FVT files")
             (version: "1.0")
             (author: "SteveXMH <stevexmh@qq.com>")
             (@subcommand decode =>
-                (about: "Decode FVT file into json file")
+                (about: "Decode FVT file into json, toml or yaml file")
                 (version: "1.0")
                 (author: "SteveXMH <stevexmh@qq.com>")
                 (@arg INPUT: +required "Sets the input file to use")
-                (@arg OUTPUT: -o --output "Set output file path, defaults s the same name with json extension")
+                (@arg OUTPUT: -o --output +takes_value "Set output file path, defaults s the same name with json extension")
+                (@arg RECURSIVE: -r --recursive conflicts_with[FROM_PACK] "Decode every FVT file found by magic in the input directory into a mirrored directory, defaults s the input directory")
+                (@arg FROM_PACK: --("from-pack") "Decode every FVT entry found by magic in the input pack file into a directory, defaults s \"[INPUT_DIR]/[INPUT_NAME]\"")
+                (@arg PASS: -p --pass +takes_value requires[FROM_PACK] "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+                (@arg KEYTABLE: -k --keytable +takes_value requires[FROM_PACK] conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+                (@arg SEED: -s --seed +takes_value requires[FROM_PACK] "Use a numeric seed instead of the hash of the password")
+                (@arg ENCODING: -e --encoding +takes_value "Text encoding: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+                (@arg CHARMAP: --charmap +takes_value "TSV file mapping characters to codes, applied after decoding")
+                (@arg DATA_FORMAT: -f --format +takes_value possible_values(&["json", "toml", "yaml"]) "Output format, defaults is json")
             )
             (@subcommand encode =>
-                (about: "Encode json file into FVT file")
+                (about: "Encode json, toml or yaml file into FVT file, the format is detected by extension")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg INPUT: +required "Sets the input file to use")
+                (@arg OUTPUT: -o --output +takes_value "Set output file path, defaults s the same name with FVT extension")
+                (@arg RECURSIVE: -r --recursive "Encode every json, toml or yaml file in the input directory into a mirrored directory, defaults s the input directory")
+                (@arg OVERFLOW: --overflow +takes_value possible_values(&["error", "split", "truncate"]) "How to handle texts longer than 255 encoded bytes, defaults is error")
+                (@arg ENCODING: -e --encoding +takes_value "Text encoding of the output, defaults is the one recorded in json or shift-jis")
+                (@arg STRICT: --strict "Fail on characters that can't be encoded instead of warning")
+                (@arg CHARMAP: --charmap +takes_value "TSV file mapping characters to codes, applied before encoding")
+            )
+            (@subcommand export =>
+                (about: "Export FVT file into a subtitle file and a json sidecar")
                 (version: "1.0")
                 (author: "SteveXMH <stevexmh@qq.com>")
                 (@arg INPUT: +required "Sets the input file to use")
-                (@arg OUTPUT: -o --output "Set output file path, defaults s the same name with json extension")
+                (@arg OUTPUT: -o --output +takes_value "Set output file path, defaults s the same name with the format extension")
+                (@arg FORMAT: -f --format +takes_value possible_values(&["srt", "ass", "vtt"]) "Subtitle format, defaults is srt")
+                (@arg START: --start +takes_value "u32 field used as the start time in milliseconds, defaults is u32_unknown0")
+                (@arg END: --end +takes_value "u32 field used as the end time in milliseconds, defaults is the start of the next record")
+                (@arg ENCODING: -e --encoding +takes_value "Text encoding: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+                (@arg CHARMAP: --charmap +takes_value "TSV file mapping characters to codes, applied after decoding")
+            )
+            (@subcommand import =>
+                (about: "Import a subtitle file back into FVT file with its json sidecar")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg INPUT: +required "Sets the input file to use")
+                (@arg OUTPUT: -o --output +takes_value "Set output file path, defaults s the same name with FVT extension")
+                (@arg SIDECAR: --sidecar +takes_value "Set sidecar file path, defaults s the input path with json extension appended")
+                (@arg OVERFLOW: --overflow +takes_value possible_values(&["error", "split", "truncate"]) "How to handle texts longer than 255 encoded bytes, defaults is error")
+                (@arg FORMAT: -f --format +takes_value possible_values(&["srt", "ass", "vtt"]) "Subtitle format, defaults is the input extension")
+                (@arg START: --start +takes_value "u32 field used as the start time in milliseconds, defaults is u32_unknown0")
+                (@arg END: --end +takes_value "u32 field used as the end time in milliseconds, defaults is the start of the next record")
+                (@arg ENCODING: -e --encoding +takes_value "Text encoding of the output, defaults is the one recorded in json or shift-jis")
+                (@arg STRICT: --strict "Fail on characters that can't be encoded instead of warning")
+                (@arg CHARMAP: --charmap +takes_value "TSV file mapping characters to codes, applied before encoding")
+            )
+            (@subcommand catalog =>
+                (about: "Translation catalog of every FVT text in a game")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@subcommand export =>
+                    (about: "Collect FVT texts of a directory or pack file into a CSV, PO or XLIFF catalog")
+                    (version: "1.0")
+                    (author: "SteveXMH <stevexmh@qq.com>")
+                    (@arg INPUT: +required "Sets the input directory to use")
+                    (@arg OUTPUT: +required "Set output catalog file path")
+                    (@arg FORMAT: -f --format +takes_value possible_values(&["csv", "po", "xliff"]) "Catalog format, defaults is the output extension")
+                    (@arg FROM_PACK: --("from-pack") "Treat input as a pack file")
+                    (@arg PASS: -p --pass +takes_value requires[FROM_PACK] "Password for encrypted pack file, defaults is \"PackPass\" for Densha De D")
+                    (@arg KEYTABLE: -k --keytable +takes_value requires[FROM_PACK] conflicts_with[SEED] "Use a 0x10000 bytes key table file instead of the password")
+                    (@arg SEED: -s --seed +takes_value requires[FROM_PACK] "Use a numeric seed instead of the hash of the password")
+                    (@arg ENCODING: -e --encoding +takes_value "Text encoding: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+                )
+                (@subcommand import =>
+                    (about: "Write translated texts of a catalog back into the FVT files of a directory")
+                    (version: "1.0")
+                    (author: "SteveXMH <stevexmh@qq.com>")
+                    (@arg CATALOG: +required "Sets the catalog file to use")
+                    (@arg INPUT: +required "Sets the directory with FVT files to use")
+                    (@arg OUTPUT: -o --output +takes_value "Set output directory for changed files, defaults s the input directory")
+                    (@arg OVERFLOW: --overflow +takes_value possible_values(&["error", "split", "truncate"]) "How to handle texts longer than 255 encoded bytes, defaults is error")
+                    (@arg FORMAT: -f --format +takes_value possible_values(&["csv", "po", "xliff"]) "Catalog format, defaults is the catalog extension")
+                    (@arg ENCODING: -e --encoding +takes_value "Text encoding of the input FVT files: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+                    (@arg TARGET_ENCODING: --("target-encoding") +takes_value "Text encoding of the output FVT files, defaults is the input encoding")
+                    (@arg STRICT: --strict "Fail on characters that can't be encoded instead of warning")
+                )
+            )
+            (@subcommand convert =>
+                (about: "Convert FVT file or its json between the variants of different games")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg INPUT: +required "Sets the input FVT or json file to use")
+                (@arg OUTPUT: +required "Set output file path, written as json if it has json extension")
+                (@arg TO: --to +takes_value +required "Target variant: lightning (DEND_FVT), burning (D2_FVT), climax or rising (D3_FVT)")
+                (@arg DEFAULT: --default +takes_value +multiple number_of_values(1) "Value of a field missing in the source variant, as u32_unknown1=VALUE")
+            )
+            (@subcommand schema =>
+                (about: "Write the JSON Schema of decoded FVT json for editors to validate against")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg OUTPUT: -o --output +takes_value "Set output file path, defaults s printing to stdout")
+            )
+            (@subcommand info =>
+                (about: "Detect the FVT variant of files and describe their layout")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg INPUT: +required +multiple "Sets the input files to use")
+                (@arg ENCODING: -e --encoding +takes_value "Text encoding: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
+            )
+            (@subcommand analyze =>
+                (about: "Report value distributions and correlations of FVT fields in a directory")
+                (version: "1.0")
+                (author: "SteveXMH <stevexmh@qq.com>")
+                (@arg INPUT: +required "Sets the input directory to use")
+                (@arg ENCODING: -e --encoding +takes_value "Text encoding: shift-jis, cp932, gbk, utf-8 or another WHATWG label, defaults is shift-jis")
             )
         )
     );
@@ -148,10 +886,10 @@ fn main() -> Result<()> {
     if let Some(subcommand) = matched.subcommand_matches("unpack") {
         let input = subcommand.value_of("INPUT").expect("Input is not provided");
         let output = subcommand.value_of("OUTPUT");
-        let pass = subcommand.value_of("PASS");
 
         let input = std::path::Path::new(input);
-        let output = if let Some(output) = output {
+        let to_archive = subcommand.value_of("TO_ARCHIVE");
+        let output = if let Some(output) = to_archive.or(output) {
             output.to_owned()
         } else {
             let output_path = std::path::Path::new(input).parent().unwrap();
@@ -162,38 +900,103 @@ fn main() -> Result<()> {
         unpack(
             input,
             std::path::Path::new(&output),
-            pass.unwrap_or("PackPass"),
+            to_archive.is_some(),
+            key_table_of(subcommand)?,
+            encoding_of(subcommand)?,
+            &kinds_of(subcommand)?,
+            &converters_of(subcommand)?,
+        )
+    } else if let Some(subcommand) = matched.subcommand_matches("mount") {
+        mount(subcommand)
+    } else if let Some(subcommand) = matched.subcommand_matches("serve") {
+        let input = subcommand.value_of("INPUT").expect("Input is not provided");
+        let port = subcommand.value_of("PORT").unwrap_or("8080");
+        let port = port
+            .parse()
+            .map_err(|_| Error::msg(format!("Invalid port: {}", port)))?;
+        serve(
+            Path::new(input),
+            key_table_of(subcommand)?,
+            encoding_of(subcommand)?,
+            charmap_of(subcommand)?,
+            port,
+        )
+    } else if let Some(subcommand) = matched.subcommand_matches("tui") {
+        let input = Path::new(subcommand.value_of("INPUT").expect("Input is not provided"));
+        let output = match subcommand.value_of("OUTPUT") {
+            Some(output) => PathBuf::from(output),
+            None => input.parent().unwrap().join(input.file_stem().unwrap()),
+        };
+        tui(
+            input,
+            &output,
+            key_table_of(subcommand)?,
+            encoding_of(subcommand)?,
+            charmap_of(subcommand)?,
+        )
+    } else if let Some(subcommand) = matched.subcommand_matches("list") {
+        let input = subcommand.value_of("INPUT").expect("Input is not provided");
+        list(
+            Path::new(input),
+            key_table_of(subcommand)?,
+            encoding_of(subcommand)?,
+            &kinds_of(subcommand)?,
         )
     } else if let Some(subcommand) = matched.subcommand_matches("pack") {
         let input = subcommand.value_of("INPUT").expect("Input is not provided");
         let output = subcommand.value_of("OUTPUT");
-        let pass = subcommand.value_of("PASS");
 
         let input = std::path::Path::new(input);
+        let from_archive = subcommand.is_present("FROM_ARCHIVE");
         let output = if let Some(output) = output {
             output.to_owned()
         } else {
             let output_path = std::path::Path::new(input);
             let output_path = output_path.parent().unwrap();
-            let name = input
-                .file_name()
+            let name = if from_archive {
+                input.file_stem()
+            } else {
+                input.file_name()
+            };
+            let name = name
                 .ok_or(Error::msg("Can't get name of input directory"))
                 .unwrap()
                 .to_str()
                 .unwrap()
                 .to_owned();
-            let output_path = output_path.join(&format!("{}.Pack", name));
+            let output_path = output_path.join(format!("{}.Pack", name));
             output_path.to_str().unwrap().to_owned()
         };
 
         pack(
             input,
+            from_archive,
             std::path::Path::new(&output),
-            pass.unwrap_or("PackPass"),
+            key_table_of(subcommand)?,
+            encoding_of(subcommand)?,
+            &converters_of(subcommand)?,
         )
+    } else if let Some(subcommand) = matched.subcommand_matches("keytable") {
+        if let Some(subcommand) = subcommand.subcommand_matches("dump") {
+            let output = subcommand
+                .value_of("OUTPUT")
+                .expect("Output is not provided");
+            keytable_dump(std::path::Path::new(output), key_table_of(subcommand)?)
+        } else {
+            println!("{}", matched.usage());
+            Ok(())
+        }
     } else if let Some(subcommand) = matched.subcommand_matches("fvt") {
         if let Some(subcommand) = subcommand.subcommand_matches("encode") {
             let input = subcommand.value_of("INPUT").expect("Input is not provided");
+            if subcommand.is_present("RECURSIVE") {
+                let output = subcommand.value_of("OUTPUT").unwrap_or(input);
+                return fvt_encode_dir(
+                    Path::new(input),
+                    Path::new(output),
+                    &encode_options_of(subcommand, "ENCODING")?,
+                );
+            }
             let output = subcommand.value_of("OUTPUT");
             let input = std::path::Path::new(input);
             let output = if let Some(output) = output {
@@ -208,12 +1011,36 @@ fn main() -> Result<()> {
                     .to_str()
                     .unwrap()
                     .to_owned();
-                let output_path = output_path.join(&format!("{}.FVT", name));
+                let output_path = output_path.join(format!("{}.FVT", name));
                 output_path.to_str().unwrap().to_owned()
             };
-            fvt_encode(input, std::path::Path::new(&output))
+            fvt_encode(
+                input,
+                std::path::Path::new(&output),
+                &encode_options_of(subcommand, "ENCODING")?,
+            )
         } else if let Some(subcommand) = subcommand.subcommand_matches("decode") {
             let input = subcommand.value_of("INPUT").expect("Input is not provided");
+            if subcommand.is_present("RECURSIVE") {
+                let output = subcommand.value_of("OUTPUT").unwrap_or(input);
+                return fvt_decode_dir(
+                    Path::new(input),
+                    Path::new(output),
+                    &decode_options_of(subcommand)?,
+                );
+            } else if subcommand.is_present("FROM_PACK") {
+                let input = Path::new(input);
+                let output = match subcommand.value_of("OUTPUT") {
+                    Some(output) => PathBuf::from(output),
+                    None => sibling_path(input, "")?,
+                };
+                return fvt_decode_pack(
+                    input,
+                    &output,
+                    key_table_of(subcommand)?,
+                    &decode_options_of(subcommand)?,
+                );
+            }
             let output = subcommand.value_of("OUTPUT");
             let input = std::path::Path::new(input);
             let output = if let Some(output) = output {
@@ -228,10 +1055,128 @@ fn main() -> Result<()> {
                     .to_str()
                     .unwrap()
                     .to_owned();
-                let output_path = output_path.join(&format!("{}.json", name));
+                let output_path = output_path.join(format!(
+                    "{}.{}",
+                    name,
+                    decode_options_of(subcommand)?.format.extension()
+                ));
                 output_path.to_str().unwrap().to_owned()
             };
-            fvt_decode(input, std::path::Path::new(&output))
+            fvt_decode(
+                input,
+                std::path::Path::new(&output),
+                &decode_options_of(subcommand)?,
+            )
+        } else if let Some(subcommand) = subcommand.subcommand_matches("export") {
+            let input = Path::new(subcommand.value_of("INPUT").expect("Input is not provided"));
+            let format = subcommand.value_of("FORMAT").unwrap_or("srt");
+            let output = match subcommand.value_of("OUTPUT") {
+                Some(output) => PathBuf::from(output),
+                None => sibling_path(input, format)?,
+            };
+            fvt_export(
+                input,
+                &output,
+                format.parse()?,
+                &timing_of(subcommand)?,
+                &decode_options_of(subcommand)?,
+            )
+        } else if let Some(subcommand) = subcommand.subcommand_matches("import") {
+            let input = Path::new(subcommand.value_of("INPUT").expect("Input is not provided"));
+            let format = match subcommand.value_of("FORMAT") {
+                Some(format) => format.parse()?,
+                None => input
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .ok_or_else(|| Error::msg("Can't detect subtitle format, use --format"))?
+                    .parse()?,
+            };
+            let output = match subcommand.value_of("OUTPUT") {
+                Some(output) => PathBuf::from(output),
+                None => sibling_path(input, "FVT")?,
+            };
+            let sidecar = match subcommand.value_of("SIDECAR") {
+                Some(sidecar) => PathBuf::from(sidecar),
+                None => sidecar_path(input),
+            };
+            fvt_import(
+                input,
+                &sidecar,
+                &output,
+                format,
+                &timing_of(subcommand)?,
+                &encode_options_of(subcommand, "ENCODING")?,
+            )
+        } else if let Some(subcommand) = subcommand.subcommand_matches("catalog") {
+            if let Some(subcommand) = subcommand.subcommand_matches("export") {
+                let input = Path::new(subcommand.value_of("INPUT").expect("Input is not provided"));
+                let output = Path::new(
+                    subcommand
+                        .value_of("OUTPUT")
+                        .expect("Output is not provided"),
+                );
+                let key_table = if subcommand.is_present("FROM_PACK") {
+                    Some(key_table_of(subcommand)?)
+                } else {
+                    None
+                };
+                let format = catalog_format_of(subcommand, output)?;
+                fvt_catalog_export(input, output, format, key_table, encoding_of(subcommand)?)
+            } else if let Some(subcommand) = subcommand.subcommand_matches("import") {
+                let catalog = Path::new(
+                    subcommand
+                        .value_of("CATALOG")
+                        .expect("Catalog is not provided"),
+                );
+                let input = subcommand.value_of("INPUT").expect("Input is not provided");
+                let output = subcommand.value_of("OUTPUT").unwrap_or(input);
+                let format = catalog_format_of(subcommand, catalog)?;
+                fvt_catalog_import(
+                    catalog,
+                    Path::new(input),
+                    Path::new(output),
+                    format,
+                    encoding_of(subcommand)?,
+                    &encode_options_of(subcommand, "TARGET_ENCODING")?,
+                )
+            } else {
+                println!("{}", matched.usage());
+                Ok(())
+            }
+        } else if let Some(subcommand) = subcommand.subcommand_matches("convert") {
+            let input = subcommand.value_of("INPUT").expect("Input is not provided");
+            let output = subcommand
+                .value_of("OUTPUT")
+                .expect("Output is not provided");
+            let mut defaults = ConvertDefaults::default();
+            for assignment in subcommand.values_of("DEFAULT").into_iter().flatten() {
+                defaults.set(assignment)?;
+            }
+            fvt_convert(
+                Path::new(input),
+                Path::new(output),
+                subcommand.value_of("TO").unwrap().parse()?,
+                &defaults,
+            )
+        } else if let Some(subcommand) = subcommand.subcommand_matches("schema") {
+            let schema = serde_json::to_string_pretty(&fvt::schema::schema())?;
+            match subcommand.value_of("OUTPUT") {
+                Some(output) => std::fs::write(output, schema)?,
+                None => println!("{}", schema),
+            }
+            Ok(())
+        } else if let Some(subcommand) = subcommand.subcommand_matches("info") {
+            let encoding = encoding_of(subcommand)?;
+            for (i, input) in subcommand.values_of("INPUT").unwrap().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                fvt_info(Path::new(input), encoding)?;
+            }
+            Ok(())
+        } else if let Some(subcommand) = subcommand.subcommand_matches("analyze") {
+            let input = subcommand.value_of("INPUT").expect("Input is not provided");
+            fvt_analyze(std::path::Path::new(input), encoding_of(subcommand)?)
         } else {
             println!("{}", matched.usage());
             Ok(())
@@ -0,0 +1,827 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 将 Pack 文件挂载为目录（Linux FUSE），读取时即时解密
+//!
+//! 可写挂载时修改与新建的文件保存在内存中的覆盖层，原 Pack 不变，
+//! 卸载后通过 `KCAPPackWriter` 重建整个 Pack，或只将改动写为补丁 Pack。
+
+use anyhow::{Error, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::kcap::{entry_path, KCAPEntryData, KCAPPackReader, KCAPPackWriter, NAME_LENGTH};
+use crate::text::TextEncoding;
+
+#[cfg(feature = "mount")]
+mod fuse;
+
+/// 根目录的节点号
+pub const ROOT: u64 = 1;
+
+/// 条目大小以 32 位记录，覆盖层中的文件不能更大
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+
+/// 覆盖层中的文件在卸载前都保存在内存中，默认只允许写入不超过 64 MiB 的文件
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 64 << 20;
+
+/// 错误为 libc 的 errno
+pub type FsResult<T> = std::result::Result<T, i32>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NodeKind {
+    Dir,
+    File,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attr {
+    pub ino: u64,
+    pub kind: NodeKind,
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub writable: bool,
+}
+
+#[derive(Debug)]
+enum FileData {
+    /// Pack 中的条目序号
+    Entry(usize),
+    /// 修改或新建后的内容，以及修改前对应的条目序号
+    Overlay(Vec<u8>, Option<usize>),
+}
+
+#[derive(Debug)]
+enum Node {
+    Dir(BTreeMap<OsString, u64>),
+    File { data: FileData, mtime: SystemTime },
+}
+
+/// 卸载后写回改动的方式
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteBack {
+    /// 重建包含所有文件的 Pack，可以是挂载的 Pack 本身
+    Rebuild(PathBuf),
+    /// 只将修改、新建与改名的文件写入另一个 Pack，无法记录删除
+    Patch(PathBuf),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    pub allow_other: bool,
+    /// 为 `None` 时只读挂载
+    pub write_back: Option<WriteBack>,
+    /// 条目名的编码，新建与改名时按此检查
+    pub encoding: TextEncoding,
+    /// 可写入的最大文件大小，为 `None` 时为 `DEFAULT_MAX_FILE_SIZE`
+    pub max_file_size: Option<u64>,
+}
+
+/// 以 Pack 的条目组成的目录树，节点号为 `nodes` 的下标加一
+pub struct PackFs {
+    pack: Arc<KCAPPackReader>,
+    nodes: Vec<Node>,
+    mtime: SystemTime,
+    writable: bool,
+    modified: bool,
+    /// 因与目录同名而无法显示的条目，重建时原样保留
+    hidden: Vec<usize>,
+    /// 条目名的编码，新建与改名时检查能否写回
+    encoding: TextEncoding,
+    /// 覆盖层中文件的最大大小，超过时写入与截断返回 `EFBIG`，不能超过 32 位
+    pub max_file_size: u64,
+}
+
+impl PackFs {
+    pub fn new(pack: KCAPPackReader, writable: bool, encoding: TextEncoding) -> Result<Self> {
+        let mtime = pack.file.metadata()?.modified()?;
+        let mut fs = Self {
+            pack: Arc::new(pack),
+            nodes: vec![Node::Dir(BTreeMap::new())],
+            mtime,
+            writable,
+            modified: false,
+            hidden: Vec::new(),
+            encoding,
+            max_file_size: DEFAULT_MAX_FILE_SIZE,
+        };
+        for index in 0..fs.pack.entries.len() {
+            let path = entry_path(&fs.pack.entries[index].name);
+            if let Err(name) = fs.insert(&path, index) {
+                fs.hidden.push(index);
+                eprintln!(
+                    "WARN: Entry {} is hidden, {:?} is both a file and a directory",
+                    fs.pack.entries[index].name, name
+                );
+            }
+        }
+        Ok(fs)
+    }
+
+    /// 是否有过改动
+    pub fn is_modified(&self) -> bool {
+        self.modified
+    }
+
+    /// 按路径插入文件，与已有节点冲突时返回冲突的名称
+    fn insert(&mut self, path: &Path, index: usize) -> std::result::Result<(), OsString> {
+        let names: Vec<&OsStr> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .collect();
+        let mut dir = ROOT;
+        for (i, name) in names.iter().enumerate() {
+            let is_file = i + 1 == names.len();
+            let next = self.nodes.len() as u64 + 1;
+            let children = match &mut self.nodes[dir as usize - 1] {
+                Node::Dir(children) => children,
+                Node::File { .. } => return Err(name.to_os_string()),
+            };
+            match children.get(*name) {
+                Some(_) if is_file => return Err(name.to_os_string()),
+                Some(&child) => dir = child,
+                None => {
+                    children.insert(name.to_os_string(), next);
+                    self.nodes.push(if is_file {
+                        Node::File {
+                            data: FileData::Entry(index),
+                            mtime: self.mtime,
+                        }
+                    } else {
+                        Node::Dir(BTreeMap::new())
+                    });
+                    dir = next;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn node(&self, ino: u64) -> FsResult<&Node> {
+        (ino as usize)
+            .checked_sub(1)
+            .and_then(|i| self.nodes.get(i))
+            .ok_or(libc::ENOENT)
+    }
+
+    fn node_mut(&mut self, ino: u64) -> FsResult<&mut Node> {
+        (ino as usize)
+            .checked_sub(1)
+            .and_then(move |i| self.nodes.get_mut(i))
+            .ok_or(libc::ENOENT)
+    }
+
+    fn children(&self, ino: u64) -> FsResult<&BTreeMap<OsString, u64>> {
+        match self.node(ino)? {
+            Node::Dir(children) => Ok(children),
+            Node::File { .. } => Err(libc::ENOTDIR),
+        }
+    }
+
+    /// 检查可写并记录改动
+    fn modify(&mut self) -> FsResult<()> {
+        if !self.writable {
+            return Err(libc::EROFS);
+        }
+        self.modified = true;
+        Ok(())
+    }
+
+    /// 取得文件在覆盖层中的内容，首次修改时从 Pack 中复制
+    fn overlay(&mut self, ino: u64) -> FsResult<&mut Vec<u8>> {
+        self.modify()?;
+        let pack = self.pack.clone();
+        match self.node_mut(ino)? {
+            Node::File { data, mtime } => {
+                if let FileData::Entry(index) = *data {
+                    let size = pack.entries[index].size;
+                    let content = pack.read_range(index, 0, size).map_err(|_| libc::EIO)?;
+                    *data = FileData::Overlay(content, Some(index));
+                }
+                *mtime = SystemTime::now();
+                match data {
+                    FileData::Overlay(content, _) => Ok(content),
+                    FileData::Entry(_) => unreachable!(),
+                }
+            }
+            Node::Dir(_) => Err(libc::EISDIR),
+        }
+    }
+
+    fn add_node(&mut self, parent: u64, name: &OsStr, node: Node) -> FsResult<u64> {
+        self.modify()?;
+        if self.children(parent)?.contains_key(name) {
+            return Err(libc::EEXIST);
+        }
+        self.check_name(parent, name, None)?;
+        let ino = self.nodes.len() as u64 + 1;
+        self.nodes.push(node);
+        if let Node::Dir(children) = self.node_mut(parent)? {
+            children.insert(name.to_os_string(), ino);
+        }
+        Ok(ino)
+    }
+
+    /// 检查 `name` 放在 `parent` 下（连同 `ino` 之下的文件）能否写回 Pack：
+    /// 含 `\` 或无法按编码表示时为 `EINVAL`，条目名超过 `NAME_LENGTH` 字节时为 `ENAMETOOLONG`
+    fn check_name(&self, parent: u64, name: &OsStr, ino: Option<u64>) -> FsResult<()> {
+        let name = name
+            .to_str()
+            .filter(|name| !name.contains('\\'))
+            .ok_or(libc::EINVAL)?;
+        self.encoding
+            .encode_checked(name, true)
+            .map_err(|_| libc::EINVAL)?;
+        let path = match self.path(parent) {
+            Some(prefix) if !prefix.is_empty() => format!("{}\\{}", prefix, name),
+            _ => name.to_string(),
+        };
+        let below = ino.map(|ino| self.files_below(ino)).unwrap_or_default();
+        let too_long = |name: &str| self.encoding.encode(name).0.len() > NAME_LENGTH;
+        if too_long(&path)
+            || below
+                .iter()
+                .any(|rest| too_long(&format!("{}\\{}", path, rest)))
+        {
+            return Err(libc::ENAMETOOLONG);
+        }
+        Ok(())
+    }
+
+    /// 以 `\` 连接的节点路径，根目录为空
+    fn path(&self, ino: u64) -> Option<String> {
+        fn find(fs: &PackFs, dir: u64, ino: u64) -> Option<String> {
+            match fs.node(dir) {
+                Ok(Node::Dir(children)) => children.iter().find_map(|(name, &child)| {
+                    let name = name.to_string_lossy();
+                    if child == ino {
+                        Some(name.into_owned())
+                    } else {
+                        find(fs, child, ino).map(|rest| format!("{}\\{}", name, rest))
+                    }
+                }),
+                _ => None,
+            }
+        }
+        if ino == ROOT {
+            Some(String::new())
+        } else {
+            find(self, ROOT, ino)
+        }
+    }
+
+    /// `ino` 之下所有文件相对于它的路径
+    fn files_below(&self, ino: u64) -> Vec<String> {
+        match self.node(ino) {
+            Ok(Node::Dir(children)) => children
+                .iter()
+                .flat_map(|(name, &child)| {
+                    let name = name.to_string_lossy();
+                    let below = self.files_below(child);
+                    if below.is_empty() {
+                        vec![name.into_owned()]
+                    } else {
+                        below
+                            .into_iter()
+                            .map(|rest| format!("{}\\{}", name, rest))
+                            .collect()
+                    }
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn is_descendant(&self, ino: u64, ancestor: u64) -> bool {
+        ino == ancestor
+            || match self.node(ancestor) {
+                Ok(Node::Dir(children)) => children
+                    .values()
+                    .any(|&child| self.is_descendant(ino, child)),
+                _ => false,
+            }
+    }
+
+    /// 以 `\` 连接的条目名列出所有文件
+    fn take_files(&mut self) -> Vec<(String, FileData)> {
+        let mut files = Vec::new();
+        let mut stack = vec![(String::new(), ROOT)];
+        while let Some((prefix, ino)) = stack.pop() {
+            let node = std::mem::replace(
+                &mut self.nodes[ino as usize - 1],
+                Node::Dir(BTreeMap::new()),
+            );
+            match node {
+                Node::Dir(children) => {
+                    for (name, child) in children {
+                        let name = name.to_string_lossy();
+                        let name = if prefix.is_empty() {
+                            name.into_owned()
+                        } else {
+                            format!("{}\\{}", prefix, name)
+                        };
+                        stack.push((name, child));
+                    }
+                }
+                Node::File { data, .. } => files.push((prefix, data)),
+            }
+        }
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    /// 将改动写回，返回写入的条目数
+    ///
+    /// 写回失败时将修改与新建的文件保存到 `<输出>.recovered` 目录，以免丢失。
+    pub fn write_back(mut self, write_back: &WriteBack) -> Result<usize> {
+        let pack = self.pack.clone();
+        let files = self.take_files();
+        let mut writer = KCAPPackWriter::with_key_table(Some(pack.key_table.clone()));
+        writer.encoding = self.encoding;
+        let (output, patch) = match write_back {
+            WriteBack::Rebuild(output) => (output, false),
+            WriteBack::Patch(output) => (output, true),
+        };
+        let mut kept: BTreeSet<usize> = self.hidden.iter().copied().collect();
+        if !patch {
+            for &index in &self.hidden {
+                writer.add_pack_entry(pack.clone(), index, &pack.entries[index].name);
+            }
+        }
+        for (name, data) in files {
+            let origin = match data {
+                FileData::Entry(index) => Some(index),
+                FileData::Overlay(_, origin) => origin,
+            };
+            // 路径未变时沿用原来的条目名
+            let name = match origin {
+                Some(index) => {
+                    kept.insert(index);
+                    let original = &pack.entries[index].name;
+                    if entry_path(original) == entry_path(&name) {
+                        original.clone()
+                    } else {
+                        name
+                    }
+                }
+                None => name,
+            };
+            match data {
+                FileData::Entry(index) if patch && pack.entries[index].name == name => {}
+                FileData::Entry(index) => writer.add_pack_entry(pack.clone(), index, &name),
+                FileData::Overlay(content, _) => writer.add_data(content, &name),
+            }
+        }
+        if patch {
+            for (index, entry) in pack.entries.iter().enumerate() {
+                if !kept.contains(&index) {
+                    eprintln!(
+                        "WARN: Removal of {} can't be recorded in a patch",
+                        entry.name
+                    );
+                }
+            }
+        }
+        let count = writer.entries.len();
+        if let Err(err) = writer.write_file(output) {
+            return Err(match recover(&writer, output) {
+                Ok(Some(dir)) => Error::msg(format!(
+                    "{}, edited files were saved to {}",
+                    err,
+                    dir.display()
+                )),
+                Ok(None) => err,
+                Err(recover_err) => Error::msg(format!(
+                    "{}, and edited files can't be saved: {}",
+                    err, recover_err
+                )),
+            });
+        }
+        Ok(count)
+    }
+
+    pub fn lookup(&mut self, parent: u64, name: &OsStr) -> FsResult<Attr> {
+        let ino = *self.children(parent)?.get(name).ok_or(libc::ENOENT)?;
+        self.getattr(ino)
+    }
+
+    pub fn getattr(&mut self, ino: u64) -> FsResult<Attr> {
+        let (kind, size, mtime) = match self.node(ino)? {
+            Node::Dir(_) => (NodeKind::Dir, 0, self.mtime),
+            Node::File { data, mtime } => {
+                let size = match data {
+                    FileData::Entry(index) => self.pack.entries[*index].size as u64,
+                    FileData::Overlay(content, _) => content.len() as u64,
+                };
+                (NodeKind::File, size, *mtime)
+            }
+        };
+        Ok(Attr {
+            ino,
+            kind,
+            size,
+            mtime,
+            writable: self.writable,
+        })
+    }
+
+    pub fn readdir(&mut self, ino: u64) -> FsResult<Vec<(u64, NodeKind, OsString)>> {
+        Ok(self
+            .children(ino)?
+            .iter()
+            .map(|(name, &child)| {
+                let kind = match self.nodes[child as usize - 1] {
+                    Node::Dir(_) => NodeKind::Dir,
+                    Node::File { .. } => NodeKind::File,
+                };
+                (child, kind, name.clone())
+            })
+            .collect())
+    }
+
+    pub fn read(&mut self, ino: u64, offset: u64, size: u32) -> FsResult<Vec<u8>> {
+        match self.node(ino)? {
+            Node::File {
+                data: FileData::Entry(index),
+                ..
+            } => self
+                .pack
+                .read_range(*index, offset as usize, size as usize)
+                .map_err(|_| libc::EIO),
+            Node::File {
+                data: FileData::Overlay(content, _),
+                ..
+            } => {
+                let start = (offset as usize).min(content.len());
+                let end = (start + size as usize).min(content.len());
+                Ok(content[start..end].to_vec())
+            }
+            Node::Dir(_) => Err(libc::EISDIR),
+        }
+    }
+
+    /// 只读挂载时拒绝以写方式打开
+    pub fn open(&mut self, ino: u64, flags: i32) -> FsResult<()> {
+        let attr = self.getattr(ino)?;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            if !self.writable {
+                return Err(libc::EROFS);
+            }
+            if attr.kind == NodeKind::Dir {
+                return Err(libc::EISDIR);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn setattr(&mut self, ino: u64, size: Option<u64>) -> FsResult<Attr> {
+        if let Some(size) = size {
+            if size > self.max_file_size.min(MAX_FILE_SIZE) {
+                return Err(libc::EFBIG);
+            }
+            self.overlay(ino)?.resize(size as usize, 0);
+        }
+        self.getattr(ino)
+    }
+
+    pub fn write(&mut self, ino: u64, offset: u64, data: &[u8]) -> FsResult<u32> {
+        let end = offset
+            .checked_add(data.len() as u64)
+            .filter(|&end| end <= self.max_file_size.min(MAX_FILE_SIZE))
+            .ok_or(libc::EFBIG)? as usize;
+        let content = self.overlay(ino)?;
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[end - data.len()..end].copy_from_slice(data);
+        Ok(data.len() as u32)
+    }
+
+    pub fn create(&mut self, parent: u64, name: &OsStr) -> FsResult<Attr> {
+        let node = Node::File {
+            data: FileData::Overlay(Vec::new(), None),
+            mtime: SystemTime::now(),
+        };
+        let ino = self.add_node(parent, name, node)?;
+        self.getattr(ino)
+    }
+
+    pub fn mkdir(&mut self, parent: u64, name: &OsStr) -> FsResult<Attr> {
+        let ino = self.add_node(parent, name, Node::Dir(BTreeMap::new()))?;
+        self.getattr(ino)
+    }
+
+    pub fn unlink(&mut self, parent: u64, name: &OsStr) -> FsResult<()> {
+        let ino = *self.children(parent)?.get(name).ok_or(libc::ENOENT)?;
+        if let Node::Dir(_) = self.node(ino)? {
+            return Err(libc::EISDIR);
+        }
+        self.modify()?;
+        if let Node::Dir(children) = self.node_mut(parent)? {
+            children.remove(name);
+        }
+        Ok(())
+    }
+
+    pub fn rmdir(&mut self, parent: u64, name: &OsStr) -> FsResult<()> {
+        let ino = *self.children(parent)?.get(name).ok_or(libc::ENOENT)?;
+        if !self.children(ino)?.is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
+        self.modify()?;
+        if let Node::Dir(children) = self.node_mut(parent)? {
+            children.remove(name);
+        }
+        Ok(())
+    }
+
+    pub fn rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+    ) -> FsResult<()> {
+        let ino = *self.children(parent)?.get(name).ok_or(libc::ENOENT)?;
+        let is_dir = matches!(self.node(ino)?, Node::Dir(_));
+        if is_dir && self.is_descendant(new_parent, ino) {
+            return Err(libc::EINVAL);
+        }
+        if let Some(&target) = self.children(new_parent)?.get(new_name) {
+            match (is_dir, self.node(target)?) {
+                (true, Node::Dir(children)) if !children.is_empty() => return Err(libc::ENOTEMPTY),
+                (true, Node::File { .. }) => return Err(libc::ENOTDIR),
+                (false, Node::Dir(_)) => return Err(libc::EISDIR),
+                _ => {}
+            }
+        }
+        self.check_name(new_parent, new_name, Some(ino))?;
+        self.modify()?;
+        if let Node::Dir(children) = self.node_mut(parent)? {
+            children.remove(name);
+        }
+        if let Node::Dir(children) = self.node_mut(new_parent)? {
+            children.insert(new_name.to_os_string(), ino);
+        }
+        Ok(())
+    }
+}
+
+/// 将写入 `output` 失败的覆盖层文件按条目路径保存到 `<output>.recovered` 目录，没有时返回 `None`
+fn recover(writer: &KCAPPackWriter, output: &Path) -> Result<Option<PathBuf>> {
+    let mut dir = output.as_os_str().to_owned();
+    dir.push(".recovered");
+    let dir = PathBuf::from(dir);
+    let mut recovered = false;
+    for entry in &writer.entries {
+        if let KCAPEntryData::Memory(data) = &entry.data {
+            let path = dir.join(entry_path(&entry.name));
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&path, data)?;
+            recovered = true;
+        }
+    }
+    Ok(if recovered { Some(dir) } else { None })
+}
+
+/// 挂载并阻塞直到被卸载，可写挂载时随后写回改动，返回写入的条目数，没有改动时为 `None`
+#[cfg(feature = "mount")]
+pub fn mount(
+    pack: KCAPPackReader,
+    mountpoint: &Path,
+    options: &MountOptions,
+) -> Result<Option<usize>> {
+    let mut fs = PackFs::new(pack, options.write_back.is_some(), options.encoding)?;
+    fs.max_file_size = options.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE);
+    let session = fuse::run(&mut fs, mountpoint, options.allow_other);
+    // 会话出错时也先写回改动，以免丢失
+    let written = match &options.write_back {
+        Some(write_back) if fs.is_modified() => Some(fs.write_back(write_back)),
+        _ => None,
+    };
+    match (session, written) {
+        (Ok(()), written) => written.transpose(),
+        (Err(err), Some(Ok(count))) => {
+            eprintln!("WARN: {} entries were written back before stopping", count);
+            Err(err)
+        }
+        (Err(err), Some(Err(write_err))) => Err(Error::msg(format!(
+            "{}, and the edits can't be written back: {}",
+            err, write_err
+        ))),
+        (Err(err), None) => Err(err),
+    }
+}
+
+#[test]
+fn test_pack_fs() {
+    use crate::kcap::KCAPPackWriter;
+
+    let root = std::env::temp_dir().join(format!("denshaded-mount-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let mut writer = KCAPPackWriter::new(Some("PackPass".into()));
+    writer.add_data(b"D3_FVT".to_vec(), "data\\fvt\\001.FVT");
+    writer.add_data(b"\x89PNG".to_vec(), "data\\title.png");
+    writer.add_data(b"hidden".to_vec(), "data\\title.png\\x");
+    let path = root.join("mount.Pack");
+    writer
+        .write_to(&mut std::fs::File::create(&path).unwrap())
+        .unwrap();
+    let pack = || KCAPPackReader::new(&path, "PackPass").unwrap();
+    let encoding = TextEncoding::default();
+    let mut fs = PackFs::new(pack(), false, encoding).unwrap();
+
+    let data = fs.lookup(ROOT, OsStr::new("data")).unwrap();
+    assert_eq!(data.kind, NodeKind::Dir);
+    let names: Vec<_> = fs
+        .readdir(data.ino)
+        .unwrap()
+        .into_iter()
+        .map(|(_, _, name)| name)
+        .collect();
+    assert_eq!(names, ["fvt", "title.png"]);
+    let fvt = fs.lookup(data.ino, OsStr::new("fvt")).unwrap();
+    let file = fs.lookup(fvt.ino, OsStr::new("001.FVT")).unwrap();
+    assert_eq!((file.kind, file.size), (NodeKind::File, 6));
+    assert_eq!(fs.read(file.ino, 3, 100).unwrap(), b"FVT");
+    assert_eq!(fs.lookup(ROOT, OsStr::new("none")), Err(libc::ENOENT));
+    assert_eq!(fs.open(file.ino, libc::O_WRONLY), Err(libc::EROFS));
+    assert_eq!(fs.write(file.ino, 0, b"x"), Err(libc::EROFS));
+    assert!(!fs.is_modified());
+
+    // 可写挂载：修改、新建、改名与删除
+    let mut fs = PackFs::new(pack(), true, encoding).unwrap();
+    assert_eq!(fs.write(file.ino, 0, b"D2").unwrap(), 2);
+    assert_eq!(fs.read(file.ino, 0, 100).unwrap(), b"D2_FVT");
+    let new = fs.create(fvt.ino, OsStr::new("002.FVT")).unwrap();
+    fs.write(new.ino, 2, b"new").unwrap();
+    assert_eq!(fs.read(new.ino, 0, 100).unwrap(), b"\0\0new");
+    assert_eq!(fs.setattr(new.ino, Some(3)).unwrap().size, 3);
+    // 超出限制的写入与截断被拒绝，不会分配内存
+    assert_eq!(fs.setattr(new.ino, Some(100 << 30)), Err(libc::EFBIG));
+    assert_eq!(
+        fs.write(new.ino, DEFAULT_MAX_FILE_SIZE, b"x"),
+        Err(libc::EFBIG)
+    );
+    assert_eq!(fs.write(new.ino, u64::MAX, b"x"), Err(libc::EFBIG));
+    fs.max_file_size = 4;
+    assert_eq!(fs.write(new.ino, 3, b"xy"), Err(libc::EFBIG));
+    assert_eq!(fs.write(new.ino, 3, b"x").unwrap(), 1);
+    // 调大限制也不能超过 32 位
+    fs.max_file_size = u64::MAX;
+    assert_eq!(fs.write(new.ino, u32::MAX as u64, b"x"), Err(libc::EFBIG));
+    assert_eq!(fs.setattr(new.ino, Some(3)).unwrap().size, 3);
+    assert_eq!(
+        fs.rename(data.ino, OsStr::new("fvt"), ROOT, OsStr::new("subtitle")),
+        Ok(())
+    );
+    assert_eq!(fs.rmdir(ROOT, OsStr::new("subtitle")), Err(libc::ENOTEMPTY));
+    fs.unlink(data.ino, OsStr::new("title.png")).unwrap();
+    assert!(fs.is_modified());
+
+    // 无法写回的名称被拒绝：分隔符、非 UTF-8、编码中没有的字符与超过 64 字节的条目名
+    use std::os::unix::ffi::OsStrExt;
+    let invalid = [
+        OsStr::new("a\\b"),
+        OsStr::from_bytes(b"\xff"),
+        OsStr::new("\u{1F683}"),
+    ];
+    for name in invalid {
+        assert_eq!(fs.create(data.ino, name), Err(libc::EINVAL));
+        assert_eq!(fs.mkdir(data.ino, name), Err(libc::EINVAL));
+    }
+    // "data\\" 加上 59 字节正好 64 字节
+    let long = "x".repeat(59);
+    assert!(fs.create(data.ino, OsStr::new(&long)).is_ok());
+    assert_eq!(
+        fs.mkdir(data.ino, OsStr::new(&format!("{}x", long))),
+        Err(libc::ENAMETOOLONG)
+    );
+    // 改名时检查目录下的所有文件：目录名本身不超过 64 字节，加上 "\\002.FVT" 就超过了
+    assert_eq!(
+        fs.rename(
+            ROOT,
+            OsStr::new("subtitle"),
+            ROOT,
+            OsStr::new(&"x".repeat(60))
+        ),
+        Err(libc::ENAMETOOLONG)
+    );
+    assert_eq!(
+        fs.rename(data.ino, OsStr::new(&long), ROOT, OsStr::new("電車.txt")),
+        Ok(())
+    );
+
+    let patch = root.join("patch.Pack");
+    let rebuilt = root.join("rebuilt.Pack");
+    let entries = |path: &Path| {
+        let pack = KCAPPackReader::new(path, "PackPass").unwrap();
+        let mut entries: Vec<_> = (0..pack.entries.len())
+            .map(|i| {
+                let data = pack.read_range(i, 0, pack.entries[i].size).unwrap();
+                (pack.entries[i].name.clone(), data)
+            })
+            .collect();
+        entries.sort();
+        entries
+    };
+    let mut fs = PackFs::new(pack(), true, encoding).unwrap();
+    let title = fs.lookup(data.ino, OsStr::new("title.png")).unwrap();
+    fs.write(title.ino, 0, b"\x89png").unwrap();
+    assert_eq!(fs.write_back(&WriteBack::Patch(patch.clone())).unwrap(), 1);
+    assert_eq!(
+        entries(&patch),
+        [("data\\title.png".to_string(), b"\x89png".to_vec())]
+    );
+    let mut fs = PackFs::new(pack(), true, encoding).unwrap();
+    fs.unlink(data.ino, OsStr::new("title.png")).unwrap();
+    fs.write_back(&WriteBack::Rebuild(rebuilt.clone())).unwrap();
+    // 与目录同名而隐藏的条目被保留
+    assert_eq!(
+        entries(&rebuilt),
+        [
+            ("data\\fvt\\001.FVT".to_string(), b"D3_FVT".to_vec()),
+            ("data\\title.png\\x".to_string(), b"hidden".to_vec())
+        ]
+    );
+
+    // 写回失败时修改过的文件保存到 `.recovered` 目录
+    let busy = root.join("busy.Pack");
+    std::fs::create_dir_all(busy.join("x")).unwrap();
+    let mut fs = PackFs::new(pack(), true, encoding).unwrap();
+    let new = fs.create(data.ino, OsStr::new("new.txt")).unwrap();
+    fs.write(new.ino, 0, b"new").unwrap();
+    let err = fs.write_back(&WriteBack::Rebuild(busy)).unwrap_err();
+    let recovered = root.join("busy.Pack.recovered");
+    assert!(err.to_string().contains(&*recovered.to_string_lossy()));
+    assert_eq!(
+        std::fs::read(recovered.join("data").join("new.txt")).unwrap(),
+        b"new"
+    );
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[cfg(feature = "mount")]
+#[test]
+fn test_mount() {
+    use crate::kcap::KCAPPackWriter;
+
+    let root = std::env::temp_dir().join(format!("denshaded-fuse-{}", std::process::id()));
+    let mountpoint = root.join("mnt");
+    std::fs::create_dir_all(&mountpoint).unwrap();
+    let mut writer = KCAPPackWriter::new(Some("PackPass".into()));
+    writer.add_data(b"D3_FVT".to_vec(), "data\\fvt\\001.FVT");
+    let path = root.join("fuse.Pack");
+    writer.write_file(&path).unwrap();
+    let pack = KCAPPackReader::new(&path, "PackPass").unwrap();
+    let mut fs = PackFs::new(pack, true, TextEncoding::default()).unwrap();
+
+    // 没有 FUSE 或权限不足的环境中跳过
+    let options = fuse::mount_options(false, false);
+    let fuse_fs = fuse::FuseFs::new(&mut fs, &mountpoint).unwrap();
+    let mut session = match fuser::Session::new(fuse_fs, &mountpoint, &options) {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("Skip mounting: {}", err);
+            std::fs::remove_dir_all(&root).unwrap();
+            return;
+        }
+    };
+    let mut unmounter = session.unmount_callable();
+    let (result, read, written) = std::thread::scope(|scope| {
+        let server = scope.spawn(move || session.run());
+        let file = mountpoint.join("data").join("fvt").join("001.FVT");
+        let read = std::fs::read(&file);
+        let written = std::fs::write(mountpoint.join("data").join("new.txt"), b"new");
+        unmounter.unmount().unwrap();
+        (server.join().unwrap(), read, written)
+    });
+    result.unwrap();
+    assert_eq!(read.unwrap(), b"D3_FVT");
+    written.unwrap();
+    assert!(fs.is_modified());
+    std::fs::remove_dir_all(&root).unwrap();
+}
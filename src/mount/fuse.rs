@@ -0,0 +1,304 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 通过 fuser 将 `PackFs` 挂载到目录，由 fusermount3 挂载与卸载，普通用户即可使用
+
+use anyhow::Result;
+use fuser::{
+    FileAttr, FileType, MountOption, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, Request, Session, TimeOrNow,
+};
+use std::ffi::OsStr;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+use super::{Attr, FsResult, NodeKind, PackFs};
+
+/// 缓存属性与目录项的时间
+const TTL: Duration = Duration::from_secs(1);
+
+/// 将 FUSE 请求转发给 `PackFs`，文件属于挂载点的所有者
+pub struct FuseFs<'a> {
+    fs: &'a mut PackFs,
+    uid: u32,
+    gid: u32,
+}
+
+impl<'a> FuseFs<'a> {
+    pub fn new(fs: &'a mut PackFs, mountpoint: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(mountpoint)?;
+        Ok(Self {
+            fs,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+        })
+    }
+
+    fn attr(&self, attr: Attr) -> FileAttr {
+        let perm = if attr.writable { 0o644 } else { 0o444 };
+        let (kind, perm, nlink) = match attr.kind {
+            NodeKind::Dir => (FileType::Directory, perm | 0o111, 2),
+            NodeKind::File => (FileType::RegularFile, perm, 1),
+        };
+        FileAttr {
+            ino: attr.ino,
+            size: attr.size,
+            blocks: attr.size.div_ceil(512),
+            atime: attr.mtime,
+            mtime: attr.mtime,
+            ctime: attr.mtime,
+            crtime: attr.mtime,
+            kind,
+            perm,
+            nlink,
+            uid: self.uid,
+            gid: self.gid,
+            rdev: 0,
+            blksize: 4096,
+            flags: 0,
+        }
+    }
+
+    fn reply_entry(&self, attr: FsResult<Attr>, reply: ReplyEntry) {
+        match attr {
+            Ok(attr) => reply.entry(&TTL, &self.attr(attr), 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn reply_attr(&self, attr: FsResult<Attr>, reply: ReplyAttr) {
+        match attr {
+            Ok(attr) => reply.attr(&TTL, &self.attr(attr)),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+fn reply_empty(result: FsResult<()>, reply: ReplyEmpty) {
+    match result {
+        Ok(()) => reply.ok(),
+        Err(errno) => reply.error(errno),
+    }
+}
+
+fn kind(kind: NodeKind) -> FileType {
+    match kind {
+        NodeKind::Dir => FileType::Directory,
+        NodeKind::File => FileType::RegularFile,
+    }
+}
+
+impl fuser::Filesystem for FuseFs<'_> {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let attr = self.fs.lookup(parent, name);
+        self.reply_entry(attr, reply);
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
+        let attr = self.fs.getattr(ino);
+        self.reply_attr(attr, reply);
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let attr = self.fs.setattr(ino, size);
+        self.reply_attr(attr, reply);
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let attr = self.fs.mkdir(parent, name);
+        self.reply_entry(attr, reply);
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        reply_empty(self.fs.unlink(parent, name), reply);
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        reply_empty(self.fs.rmdir(parent, name), reply);
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        new_parent: u64,
+        new_name: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        // 不支持 RENAME_NOREPLACE 与 RENAME_EXCHANGE
+        if flags != 0 {
+            return reply.error(libc::EINVAL);
+        }
+        reply_empty(self.fs.rename(parent, name, new_parent, new_name), reply);
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.fs.open(ino, flags) {
+            Ok(()) => reply.opened(0, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.fs.read(ino, offset.max(0) as u64, size) {
+            Ok(data) => reply.data(&data),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.fs.write(ino, offset.max(0) as u64, data) {
+            Ok(written) => reply.written(written),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock: u64, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _sync: bool, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let children = match self.fs.readdir(ino) {
+            Ok(children) => children,
+            Err(errno) => return reply.error(errno),
+        };
+        let dots = vec![
+            (ino, NodeKind::Dir, ".".into()),
+            (ino, NodeKind::Dir, "..".into()),
+        ];
+        let entries = dots.into_iter().chain(children).enumerate();
+        for (i, (child, node_kind, name)) in entries.skip(offset.max(0) as usize) {
+            // 缓冲区已满时停止，内核会从返回的偏移处继续读取
+            if reply.add(child, i as i64 + 1, kind(node_kind), name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.fs.create(parent, name) {
+            Ok(attr) => reply.created(&TTL, &self.attr(attr), 0, 0, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+}
+
+/// 挂载选项，与 libfuse 的默认选项一致并总是检查权限
+pub fn mount_options(read_only: bool, allow_other: bool) -> Vec<MountOption> {
+    let mut options = vec![
+        MountOption::FSName("denshaded-tools".into()),
+        MountOption::Subtype("denshaded-tools".into()),
+        MountOption::NoSuid,
+        MountOption::NoDev,
+        MountOption::DefaultPermissions,
+        if read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
+    ];
+    if allow_other {
+        options.push(MountOption::AllowOther);
+    }
+    options
+}
+
+/// 挂载并处理请求，直到被 `fusermount3 -u`、Ctrl-C 或 SIGTERM 卸载
+pub fn run(fs: &mut PackFs, mountpoint: &Path, allow_other: bool) -> Result<()> {
+    let read_only = !fs.writable;
+    let fs = FuseFs::new(fs, mountpoint)?;
+    let mut session = Session::new(fs, mountpoint, &mount_options(read_only, allow_other))?;
+    let mut unmounter = session.unmount_callable();
+    ctrlc::set_handler(move || {
+        if let Err(err) = unmounter.unmount() {
+            eprintln!("WARN: Can't unmount: {}", err);
+        }
+    })?;
+    session.run()?;
+    Ok(())
+}
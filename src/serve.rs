@@ -0,0 +1,419 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 在本机提供浏览 Pack 内容的网页
+//!
+//! `/` 列出条目，`/entry/{序号}` 按识别出的类型返回解密后的数据，`/fvt/{序号}` 以表格显示字幕。
+//! 请求由几个线程同时处理，条目支持 `Range` 请求，浏览器可以拖动播放音视频。
+
+use anyhow::{Error, Result};
+use std::fmt::Write as _;
+use std::io::Read;
+use std::sync::Arc;
+
+use crate::fvt::{subtitle, Fvt, FvtVariant};
+use crate::kcap::KCAPPackReader;
+use crate::sniff::{FileKind, FileType};
+use crate::text::{charmap::CharMap, TextEncoding};
+
+/// 流式返回条目时每次读取的字节数
+const CHUNK_LENGTH: usize = 0x10000;
+/// 处理请求的线程数
+const WORKERS: usize = 4;
+
+/// 逐块解密读取一个条目中 `offset..end` 的部分
+struct EntryReader {
+    pack: Arc<KCAPPackReader>,
+    index: usize,
+    offset: usize,
+    end: usize,
+}
+
+impl Read for EntryReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(CHUNK_LENGTH).min(self.end - self.offset);
+        let data = self
+            .pack
+            .read_range(self.index, self.offset, len)
+            .map_err(std::io::Error::other)?;
+        buf[..data.len()].copy_from_slice(&data);
+        self.offset += data.len();
+        Ok(data.len())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Page {
+    Html(String),
+    /// 条目的序号
+    Entry(usize),
+    NotFound,
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+table{border-collapse:collapse}\
+td,th{border:1px solid #ccc;padding:2px 8px;text-align:left}\
+td.num{text-align:right}";
+
+fn html(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head>\n<body>\n<h1>{}</h1>\n{}</body></html>\n",
+        escape(title),
+        STYLE,
+        escape(title),
+        body
+    )
+}
+
+/// 解析只含一个范围的 `Range` 请求头，返回含两端的字节范围
+///
+/// 无法解析或含多个范围时返回 `None`，按普通请求返回整个条目；范围超出大小时返回 `Some(None)`。
+fn parse_range(header: &str, size: usize) -> Option<Option<(usize, usize)>> {
+    let range = header.trim().strip_prefix("bytes=")?;
+    if range.contains(',') {
+        return None;
+    }
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => {
+            let suffix: usize = suffix.parse().ok()?;
+            (size.saturating_sub(suffix), size.checked_sub(1)?)
+        }
+        (start, "") => (start.parse().ok()?, size.checked_sub(1)?),
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            (start, end.min(size.checked_sub(1)?))
+        }
+    };
+    Some(Some((start, end)).filter(|_| start < size))
+}
+
+pub struct Server {
+    pack: Arc<KCAPPackReader>,
+    types: Vec<FileType>,
+    encoding: TextEncoding,
+    /// 解码字幕后应用的字符映射表
+    charmap: Option<Arc<CharMap>>,
+    title: String,
+}
+
+impl Server {
+    /// `encoding` 用于解码字幕文本，条目名的编码由打开 `pack` 时指定
+    pub fn new(
+        pack: KCAPPackReader,
+        encoding: TextEncoding,
+        charmap: Option<Arc<CharMap>>,
+        title: &str,
+    ) -> Result<Self> {
+        let types = (0..pack.entries.len())
+            .map(|i| pack.sniff(i))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            pack: Arc::new(pack),
+            types,
+            encoding,
+            charmap,
+            title: title.into(),
+        })
+    }
+
+    /// 在 `127.0.0.1:port` 上提供服务，不会返回
+    pub fn run(&self, port: u16) -> Result<()> {
+        let server = tiny_http::Server::http(("127.0.0.1", port)).map_err(Error::msg)?;
+        std::thread::scope(|scope| {
+            for _ in 0..WORKERS {
+                scope.spawn(|| {
+                    for request in server.incoming_requests() {
+                        let response = self.respond_to(&request);
+                        if let Err(err) = request.respond(response) {
+                            eprintln!("WARN: {}", err);
+                        }
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+
+    fn respond_to(
+        &self,
+        request: &tiny_http::Request,
+    ) -> tiny_http::Response<Box<dyn Read + Send>> {
+        match self.route(request.url()) {
+            Page::Html(page) => respond(
+                200,
+                "text/html; charset=utf-8",
+                Box::new(std::io::Cursor::new(page.into_bytes())),
+                None,
+            ),
+            Page::Entry(index) => {
+                let size = self.pack.entries[index].size;
+                let range = request
+                    .headers()
+                    .iter()
+                    .find(|header| header.field.equiv("Range"))
+                    .and_then(|header| parse_range(header.value.as_str(), size));
+                let (status, start, end) = match range {
+                    None => (200, 0, size),
+                    Some(Some((start, end))) => (206, start, end + 1),
+                    Some(None) => {
+                        return respond(416, "text/plain; charset=utf-8", Box::new(&b""[..]), None)
+                            .with_header(header("Content-Range", &format!("bytes */{}", size)));
+                    }
+                };
+                let mut response = respond(
+                    status,
+                    self.types[index].mime(),
+                    Box::new(EntryReader {
+                        pack: self.pack.clone(),
+                        index,
+                        offset: start,
+                        end,
+                    }),
+                    Some(end - start),
+                )
+                .with_header(header("Accept-Ranges", "bytes"));
+                if status == 206 {
+                    response = response.with_header(header(
+                        "Content-Range",
+                        &format!("bytes {}-{}/{}", start, end - 1, size),
+                    ));
+                }
+                response
+            }
+            Page::NotFound => respond(
+                404,
+                "text/plain; charset=utf-8",
+                Box::new(&b"Not found"[..]),
+                None,
+            ),
+        }
+    }
+
+    fn route(&self, url: &str) -> Page {
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let index = |id: &str| id.parse::<usize>().ok().filter(|&i| i < self.types.len());
+        match path.split('/').collect::<Vec<_>>()[..] {
+            ["", ""] => {
+                let kind = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("type="))
+                    .and_then(|kind| kind.parse().ok());
+                Page::Html(self.index_page(kind))
+            }
+            ["", "entry", id] => index(id).map_or(Page::NotFound, Page::Entry),
+            ["", "fvt", id] => match index(id) {
+                Some(i) if self.types[i].kind == FileKind::Fvt => Page::Html(self.fvt_page(i)),
+                _ => Page::NotFound,
+            },
+            _ => Page::NotFound,
+        }
+    }
+
+    fn index_page(&self, kind: Option<FileKind>) -> String {
+        let mut body = String::from("<p>Type: <a href=\"/\">all</a>");
+        for kind in &FileKind::ALL {
+            let _ = write!(body, " | <a href=\"/?type={0}\">{0}</a>", kind.name());
+        }
+        body.push_str("</p>\n<table>\n<tr><th>#</th><th>Name</th><th>Size</th><th>Encrypted</th><th>Type</th></tr>\n");
+        for (i, (entry, file_type)) in self.pack.entries.iter().zip(&self.types).enumerate() {
+            if kind.is_some_and(|kind| kind != file_type.kind) {
+                continue;
+            }
+            let preview = if file_type.kind == FileKind::Fvt {
+                format!(" <a href=\"/fvt/{}\">(text)</a>", i)
+            } else {
+                String::new()
+            };
+            let _ = writeln!(
+                body,
+                "<tr><td class=\"num\">{0}</td><td><a href=\"/entry/{0}\">{1}</a>{2}</td><td class=\"num\">{3}</td><td>{4}</td><td>{5}</td></tr>",
+                i,
+                escape(&entry.name),
+                preview,
+                entry.size,
+                if entry.encrypted { "yes" } else { "no" },
+                file_type
+            );
+        }
+        body.push_str("</table>\n");
+        html(&self.title, &body)
+    }
+
+    fn fvt_page(&self, index: usize) -> String {
+        let name = &self.pack.entries[index].name;
+        let data = self
+            .pack
+            .read_range(index, 0, self.pack.entries[index].size);
+        let fvt = data.and_then(|data| Fvt::from_read_with(&mut &data[..], self.encoding));
+        let fvt = fvt.map(|mut fvt| {
+            if let Some(charmap) = &self.charmap {
+                fvt.map_decoded(charmap);
+            }
+            fvt
+        });
+        let mut body = String::from("<p><a href=\"/\">Back</a></p>\n");
+        match fvt {
+            Ok(fvt) => {
+                let _ = writeln!(
+                    body,
+                    "<p>{} records, {}</p>",
+                    fvt.records.len(),
+                    escape(&fvt.tag)
+                );
+                // Lightning Stage 的记录只有 u32_unknown0，不显示不存在的字段
+                let fields = FvtVariant::from_tag(&fvt.tag)
+                    .map(|variant| variant.u32_fields())
+                    .unwrap_or(3);
+                body.push_str("<table>\n<tr><th>#</th>");
+                for field in 0..fields {
+                    let _ = write!(body, "<th>u32_unknown{}</th>", field);
+                }
+                body.push_str("<th>Text</th></tr>\n");
+                for (i, record) in fvt.records.iter().enumerate() {
+                    let _ = write!(body, "<tr><td class=\"num\">{}</td>", i);
+                    for field in 0..fields {
+                        let _ = write!(
+                            body,
+                            "<td class=\"num\">{}</td>",
+                            subtitle::field(record, field)
+                        );
+                    }
+                    let _ = writeln!(
+                        body,
+                        "<td>{}</td></tr>",
+                        escape(&record.text).replace('\n', "<br>")
+                    );
+                }
+                body.push_str("</table>\n");
+            }
+            Err(err) => {
+                let _ = writeln!(body, "<p>Can't decode: {}</p>", escape(&err.to_string()));
+            }
+        }
+        html(name, &body)
+    }
+}
+
+fn header(field: &str, value: &str) -> tiny_http::Header {
+    tiny_http::Header::from_bytes(field.as_bytes(), value.as_bytes()).expect("Invalid header")
+}
+
+fn respond(
+    status: u16,
+    content_type: &str,
+    body: Box<dyn Read + Send>,
+    length: Option<usize>,
+) -> tiny_http::Response<Box<dyn Read + Send>> {
+    let headers = vec![header("Content-Type", content_type)];
+    tiny_http::Response::new(status.into(), headers, body, length, None)
+}
+
+#[test]
+fn test_serve_routes() {
+    use crate::kcap::KCAPPackWriter;
+
+    let root = std::env::temp_dir().join(format!("denshaded-serve-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let fvt = b"D3_FVT\x01\0\0\0\x02\0\0\0\x03\0\0\0\x07\x07\x00<b>&\n\x93\x64";
+    let mut writer = KCAPPackWriter::new(Some("PackPass".into()));
+    writer.add_data(fvt.to_vec(), "fvt\\<001>.FVT");
+    writer.add_data(b"\x89PNG\r\n\x1a\n".to_vec(), "title.png");
+    writer.add_data(b"DEND_FVT\x05\0\0\0\x00\x02\x00ab".to_vec(), "dend.FVT");
+    let path = root.join("serve.Pack");
+    writer
+        .write_to(&mut std::fs::File::create(&path).unwrap())
+        .unwrap();
+    let pack = KCAPPackReader::new(&path, "PackPass").unwrap();
+    let server = Server::new(pack, TextEncoding::default(), None, "serve.Pack").unwrap();
+    // 条目按大小排序写入
+    let (png, dend, fvt) = (0, 1, 2);
+
+    let index = match server.route("/") {
+        Page::Html(page) => page,
+        other => panic!("{:?}", other),
+    };
+    assert!(index.contains("fvt\\&lt;001&gt;.FVT"));
+    assert!(index.contains(&format!("<a href=\"/fvt/{}\">", fvt)));
+    assert!(index.contains("image/png"));
+    match server.route("/?type=image") {
+        Page::Html(page) => assert!(!page.contains(".FVT")),
+        other => panic!("{:?}", other),
+    }
+    match server.route(&format!("/fvt/{}", fvt)) {
+        Page::Html(page) => {
+            assert!(page.contains("&lt;b&gt;&amp;<br>電"), "{}", page);
+            assert!(page.contains("<th>u32_unknown2</th>"));
+        }
+        other => panic!("{:?}", other),
+    }
+    // Lightning Stage 只显示存储了的字段
+    match server.route(&format!("/fvt/{}", dend)) {
+        Page::Html(page) => {
+            assert!(
+                page.contains("<td class=\"num\">5</td><td>ab</td>"),
+                "{}",
+                page
+            );
+            assert!(!page.contains("u32_unknown1"));
+        }
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(server.route(&format!("/entry/{}", png)), Page::Entry(png));
+    assert_eq!(server.types[png].mime(), "image/png");
+    assert_eq!(server.route(&format!("/fvt/{}", png)), Page::NotFound);
+    assert_eq!(server.route("/entry/9"), Page::NotFound);
+    assert_eq!(server.route("/favicon.ico"), Page::NotFound);
+
+    let mut reader = EntryReader {
+        pack: server.pack.clone(),
+        index: png,
+        offset: 1,
+        end: 4,
+    };
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"PNG");
+
+    assert_eq!(parse_range("bytes=0-", 10), Some(Some((0, 9))));
+    assert_eq!(parse_range("bytes=2-4", 10), Some(Some((2, 4))));
+    assert_eq!(parse_range("bytes=5-100", 10), Some(Some((5, 9))));
+    assert_eq!(parse_range("bytes=-3", 10), Some(Some((7, 9))));
+    assert_eq!(parse_range("bytes=10-", 10), Some(None));
+    assert_eq!(parse_range("bytes=0-1,4-5", 10), None);
+    assert_eq!(parse_range("items=0-1", 10), None);
+    assert_eq!(parse_range("bytes=0-", 0), None);
+    std::fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,226 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 根据开头的字节识别 Pack 条目的内容类型
+
+use anyhow::{Error, Result};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::fvt::{self, Detection};
+
+/// 识别所需的开头字节数
+pub const SNIFF_LENGTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FileKind {
+    Image,
+    Audio,
+    Video,
+    Fvt,
+    Script,
+    Archive,
+    Unknown,
+}
+
+impl FileKind {
+    pub const ALL: [Self; 7] = [
+        Self::Image,
+        Self::Audio,
+        Self::Video,
+        Self::Fvt,
+        Self::Script,
+        Self::Archive,
+        Self::Unknown,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Image => "image",
+            Self::Audio => "audio",
+            Self::Video => "video",
+            Self::Fvt => "fvt",
+            Self::Script => "script",
+            Self::Archive => "archive",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl FromStr for FileKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| Error::msg(format!("Unknown file type: {}", s)))
+    }
+}
+
+/// 内容类型，`format` 为具体格式，如 `png`、`D3_FVT`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileType {
+    pub kind: FileKind,
+    pub format: &'static str,
+}
+
+impl FileType {
+    /// 对应的 MIME 类型，没有标准类型的格式视为二进制数据
+    pub fn mime(&self) -> &'static str {
+        match self.format {
+            "png" => "image/png",
+            "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            "webp" => "image/webp",
+            "bmp" => "image/bmp",
+            "ogg" => "audio/ogg",
+            "wav" => "audio/wav",
+            "flac" => "audio/flac",
+            "mp3" => "audio/mpeg",
+            "aac" => "audio/aac",
+            "wmv" => "video/x-ms-wmv",
+            "avi" => "video/x-msvideo",
+            "mp4" => "video/mp4",
+            "mkv" => "video/x-matroska",
+            "mpeg" => "video/mpeg",
+            "xml" => "application/xml",
+            "text" => "text/plain",
+            "zip" => "application/zip",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+impl fmt::Display for FileType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            FileKind::Unknown => f.write_str(self.kind.name()),
+            _ => write!(f, "{}/{}", self.kind.name(), self.format),
+        }
+    }
+}
+
+/// 开头的标识，`offset` 处为 `magic`
+const SIGNATURES: &[(usize, &[u8], FileKind, &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", FileKind::Image, "png"),
+    (0, b"\xFF\xD8\xFF", FileKind::Image, "jpeg"),
+    (0, b"GIF8", FileKind::Image, "gif"),
+    (0, b"DDS ", FileKind::Image, "dds"),
+    (8, b"WEBP", FileKind::Image, "webp"),
+    (0, b"BM", FileKind::Image, "bmp"),
+    (0, b"OggS", FileKind::Audio, "ogg"),
+    (8, b"WAVE", FileKind::Audio, "wav"),
+    (0, b"fLaC", FileKind::Audio, "flac"),
+    (0, b"ID3", FileKind::Audio, "mp3"),
+    (0, b"\xFF\xFB", FileKind::Audio, "mp3"),
+    (0, b"\xFF\xF3", FileKind::Audio, "mp3"),
+    (0, b"\xFF\xF1", FileKind::Audio, "aac"),
+    (0, b"\xFF\xF9", FileKind::Audio, "aac"),
+    (
+        0,
+        b"\x30\x26\xB2\x75\x8E\x66\xCF\x11",
+        FileKind::Video,
+        "wmv",
+    ),
+    (8, b"AVI ", FileKind::Video, "avi"),
+    (4, b"ftyp", FileKind::Video, "mp4"),
+    (0, b"\x1A\x45\xDF\xA3", FileKind::Video, "mkv"),
+    (0, b"\x00\x00\x01\xBA", FileKind::Video, "mpeg"),
+    (0, b"\x00\x00\x01\xB3", FileKind::Video, "mpeg"),
+    (0, b"BIK", FileKind::Video, "bink"),
+    (0, b"KB2", FileKind::Video, "bink2"),
+    (0, b"CRID", FileKind::Video, "usm"),
+    (0, b"\x1BLua", FileKind::Script, "lua-bytecode"),
+    (0, b"<?xml", FileKind::Script, "xml"),
+    (0, b"KCAP", FileKind::Archive, "kcap"),
+    (0, b"PK\x03\x04", FileKind::Archive, "zip"),
+];
+
+/// 不含控制字符且能按 Shift-JIS 或 UTF-8 解码的数据视为文本脚本
+fn is_text(head: &[u8]) -> bool {
+    if head.is_empty()
+        || head
+            .iter()
+            .any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\r' | b'\n'))
+    {
+        return false;
+    }
+    // 开头的字节可能在多字节字符中间截断，只检查完整的部分
+    [encoding_rs::UTF_8, encoding_rs::SHIFT_JIS]
+        .iter()
+        .any(|encoding| {
+            let mut decoder = encoding.new_decoder_without_bom_handling();
+            let mut buf = String::with_capacity(head.len() * 3);
+            let (result, _) = decoder.decode_to_string_without_replacement(head, &mut buf, false);
+            matches!(result, encoding_rs::DecoderResult::InputEmpty)
+        })
+}
+
+/// 识别数据的类型，`head` 取数据开头 `SNIFF_LENGTH` 字节即可
+pub fn sniff(head: &[u8]) -> FileType {
+    if let Detection::Known(variant) = fvt::detect(head) {
+        return FileType {
+            kind: FileKind::Fvt,
+            format: variant.tag(),
+        };
+    }
+    for (offset, magic, kind, format) in SIGNATURES {
+        if head.get(*offset..offset + magic.len()) == Some(magic) {
+            return FileType {
+                kind: *kind,
+                format,
+            };
+        }
+    }
+    if is_text(head) {
+        return FileType {
+            kind: FileKind::Script,
+            format: "text",
+        };
+    }
+    FileType {
+        kind: FileKind::Unknown,
+        format: "",
+    }
+}
+
+#[test]
+fn test_sniff() {
+    let cases: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"),
+        (b"RIFF\x24\0\0\0WAVEfmt ", "audio/wav"),
+        (b"RIFF\x24\0\0\0AVI LIST", "video/avi"),
+        (b"\0\0\0\x20ftypisom", "video/mp4"),
+        (b"D3_FVT\x01\0\0\0", "fvt/D3_FVT"),
+        (b"DEND_FVT", "fvt/DEND_FVT"),
+        (b"KCAP\x02\0\0\0", "archive/kcap"),
+        // 在多字节字符中间截断的 Shift-JIS 文本
+        (b"function main()\r\n\t\x93\x64\x8E", "script/text"),
+        (b"\x00\x01\x02\x03", "unknown"),
+        (b"", "unknown"),
+    ];
+    for (head, expected) in cases {
+        assert_eq!(sniff(head).to_string(), *expected);
+    }
+    assert_eq!("FVT".parse::<FileKind>().unwrap(), FileKind::Fvt);
+    assert!("text".parse::<FileKind>().is_err());
+    assert_eq!(sniff(b"\x89PNG\r\n\x1a\n").mime(), "image/png");
+    assert_eq!(sniff(b"D3_FVT").mime(), "application/octet-stream");
+}
@@ -0,0 +1,252 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 字幕文本与 Pack 文件名使用的文本编码
+//!
+//! 解码不会丢失数据：无法解码或无法原样编码回去的字节写为 `\xHH`，
+//! 原文中恰好构成 `\xHH` 形式的反斜杠写为 `\x5C`，编码时再还原为原始字节。
+
+use anyhow::{Error, Result};
+use encoding_rs::Encoding;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+pub mod charmap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextEncoding(&'static Encoding);
+
+impl Default for TextEncoding {
+    fn default() -> Self {
+        Self(encoding_rs::SHIFT_JIS)
+    }
+}
+
+impl FromStr for TextEncoding {
+    type Err = Error;
+
+    /// 接受 `shift-jis`、`cp932`、`gbk`、`utf-8` 等 WHATWG 标签
+    ///
+    /// WHATWG 的 Shift_JIS 即微软的 CP932，包含 NEC 与 IBM 扩展字符。
+    fn from_str(s: &str) -> Result<Self> {
+        let label = match s.to_ascii_lowercase().as_str() {
+            "sjis" | "cp932" => "shift_jis".to_string(),
+            "utf8" => "utf-8".to_string(),
+            label => label.to_string(),
+        };
+        Encoding::for_label_no_replacement(label.as_bytes())
+            .map(Self)
+            .ok_or_else(|| Error::msg(format!("Unknown text encoding: {}", s)))
+    }
+}
+
+impl fmt::Display for TextEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.name())
+    }
+}
+
+impl Serialize for TextEncoding {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for TextEncoding {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let label = String::deserialize(deserializer)?;
+        label.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+fn hex_byte(s: &[u8]) -> Option<u8> {
+    let digit = |c: u8| (c as char).to_digit(16);
+    match s {
+        [b'\\', b'x', h, l, ..] => Some((digit(*h)? * 16 + digit(*l)?) as u8),
+        _ => None,
+    }
+}
+
+impl TextEncoding {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// 尝试从 `bytes` 开头解码出一个能原样编码回去的字符，返回字符与所用字节数
+    fn decode_char(&self, bytes: &[u8]) -> Option<(char, usize)> {
+        for len in 1..=bytes.len().min(4) {
+            let part = &bytes[..len];
+            let (decoded, error) = self.0.decode_without_bom_handling(part);
+            let mut chars = decoded.chars();
+            if let (false, Some(c), None) = (error, chars.next(), chars.next()) {
+                if self.0.encode(&decoded).0[..] == part[..] {
+                    return Some((c, len));
+                }
+            }
+        }
+        None
+    }
+
+    /// 无损解码
+    pub fn decode(&self, bytes: &[u8]) -> String {
+        self.decode_mapped(bytes, |_| None)
+    }
+
+    /// 无损解码，`mapped` 返回的字符优先于编码本身的字符
+    pub(crate) fn decode_mapped(
+        &self,
+        bytes: &[u8],
+        mapped: impl Fn(&[u8]) -> Option<(char, usize)>,
+    ) -> String {
+        let mut text = String::with_capacity(bytes.len());
+        let mut i = 0;
+        while i < bytes.len() {
+            match mapped(&bytes[i..]).or_else(|| self.decode_char(&bytes[i..])) {
+                Some(('\\', len)) if hex_byte(&bytes[i..]).is_some() => {
+                    text.push_str("\\x5C");
+                    i += len;
+                }
+                Some((c, len)) => {
+                    text.push(c);
+                    i += len;
+                }
+                None => {
+                    text.push_str(&format!("\\x{:02X}", bytes[i]));
+                    i += 1;
+                }
+            }
+        }
+        text
+    }
+
+    /// 将文本拆成可以独立编码的片段：`\xHH` 转义或单个字符
+    pub(crate) fn units<'a>(
+        &self,
+        text: &'a str,
+    ) -> impl Iterator<Item = (&'a str, Option<u8>)> + 'a {
+        let mut rest = text;
+        std::iter::from_fn(move || {
+            let c = rest.chars().next()?;
+            let (unit, byte) = match hex_byte(rest.as_bytes()) {
+                Some(byte) => (&rest[..4], Some(byte)),
+                None => (&rest[..c.len_utf8()], None),
+            };
+            rest = &rest[unit.len()..];
+            Some((unit, byte))
+        })
+    }
+
+    /// 编码文本，同时返回无法编码的字符
+    pub fn encode(&self, text: &str) -> (Vec<u8>, Vec<char>) {
+        let mut bytes = Vec::with_capacity(text.len());
+        let mut unmappable = Vec::new();
+        for (unit, byte) in self.units(text) {
+            match byte {
+                Some(byte) => bytes.push(byte),
+                None => {
+                    let (encoded, _, error) = self.0.encode(unit);
+                    if error {
+                        unmappable.extend(unit.chars());
+                    }
+                    bytes.extend_from_slice(&encoded);
+                }
+            }
+        }
+        (bytes, unmappable)
+    }
+
+    /// 编码文本，`strict` 时遇到无法编码的字符报错，否则给出警告
+    pub fn encode_checked(&self, text: &str, strict: bool) -> Result<Vec<u8>> {
+        let (bytes, unmappable) = self.encode(text);
+        if !unmappable.is_empty() {
+            let chars: String = unmappable.iter().collect();
+            let message = format!(
+                "Characters {:?} of {:?} can't be encoded in {}",
+                chars,
+                text,
+                self.0.name()
+            );
+            if strict {
+                return Err(Error::msg(message));
+            }
+            eprintln!("WARN: {}", message);
+        }
+        Ok(bytes)
+    }
+
+    /// 在字符边界把文本拆成编码后不超过 `max` 字节的若干段
+    pub fn split(&self, text: &str, max: usize) -> Vec<String> {
+        let mut parts = vec![String::new()];
+        let mut length = 0;
+        for (unit, byte) in self.units(text) {
+            // 这些编码器都没有状态，逐字编码的长度之和等于整体编码的长度
+            let unit_length = match byte {
+                Some(_) => 1,
+                None => self.0.encode(unit).0.len(),
+            };
+            if length + unit_length > max && length > 0 {
+                parts.push(String::new());
+                length = 0;
+            }
+            parts.last_mut().unwrap().push_str(unit);
+            length += unit_length;
+        }
+        parts
+    }
+}
+
+#[test]
+fn test_text_lossless() {
+    // 伪随机字节，覆盖各种无效序列
+    let mut seed = 0x12345678u32;
+    let bytes: Vec<u8> = (0..4096)
+        .map(|_| {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            (seed >> 16) as u8
+        })
+        .collect();
+    for label in &["shift-jis", "cp932", "gbk", "utf-8"] {
+        let encoding: TextEncoding = label.parse().unwrap();
+        let text = encoding.decode(&bytes);
+        assert_eq!(encoding.encode(&text), (bytes.clone(), vec![]), "{}", label);
+    }
+}
+
+#[test]
+fn test_text_escape() {
+    let sjis = TextEncoding::default();
+    assert_eq!(sjis.decode(b"\x93\x64\x8E\xD4"), "電車");
+    assert_eq!(sjis.decode(b"a\x82\xFFb"), "a\\x82\\xFFb");
+    assert_eq!(sjis.decode(b"dir\\x41\\b"), "dir\\x5Cx41\\b");
+    assert_eq!(sjis.encode("dir\\x5Cx41\\b").0, b"dir\\x41\\b");
+    // 编码时按字节处理的转义
+    assert_eq!(sjis.encode("\\x93\\x64車").0, b"\x93\x64\x8E\xD4");
+
+    let gbk: TextEncoding = "gbk".parse().unwrap();
+    assert_eq!(gbk.decode(&gbk.encode("电车").0), "电车");
+    assert_eq!(gbk.to_string(), "GBK");
+    assert_eq!("cp932".parse::<TextEncoding>().unwrap(), sjis);
+
+    assert!(sjis.encode_checked("电车", true).is_err());
+    assert_eq!(sjis.encode("电").1, ['电']);
+    assert_eq!(
+        sjis.split("ab\\x82電", 3),
+        ["ab\\x82".to_string(), "電".to_string()]
+    );
+}
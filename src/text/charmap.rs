@@ -0,0 +1,140 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 字符映射表
+//!
+//! 汉化与英化时常把 Shift-JIS 中未使用的编码换成新的字形。映射表为 TSV，每行一个字符与其编码，
+//! 如 `电<TAB>889F`，`#` 开头的行为注释。解码后按原始字节把这些编码换回字符，编码前把字符换成对应的编码。
+
+use anyhow::{Error, Result};
+use std::collections::HashMap;
+use std::path::Path;
+
+use super::TextEncoding;
+
+#[derive(Debug, Clone, Default)]
+pub struct CharMap {
+    to_code: HashMap<char, Vec<u8>>,
+    from_code: HashMap<Vec<u8>, char>,
+    /// 最长编码的字节数
+    max_len: usize,
+}
+
+fn parse_code(code: &str) -> Option<Vec<u8>> {
+    let code = code.strip_prefix("0x").unwrap_or(code);
+    if code.is_empty() || code.len() > 8 || code.len() % 2 != 0 {
+        return None;
+    }
+    (0..code.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(code.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+impl CharMap {
+    pub fn from_tsv(data: &str) -> Result<Self> {
+        let mut map = Self::default();
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: &str| Error::msg(format!("Line {}: {}", i + 1, message));
+            let mut columns = line.split('\t');
+            let (c, code) = match (columns.next(), columns.next()) {
+                (Some(c), Some(code)) => (c, code.trim()),
+                _ => return Err(error("Expected a character and a code separated by a tab")),
+            };
+            let mut chars = c.chars();
+            let c = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(error(&format!("{:?} is not a single character", c))),
+            };
+            let code = parse_code(code)
+                .ok_or_else(|| error(&format!("{:?} is not a code of 1 to 4 hex bytes", code)))?;
+            if map.to_code.contains_key(&c) {
+                return Err(error(&format!("Character {:?} is mapped twice", c)));
+            }
+            if map.from_code.contains_key(&code) {
+                return Err(error(&format!("Code {:02X?} is mapped twice", code)));
+            }
+            map.max_len = map.max_len.max(code.len());
+            map.to_code.insert(c, code.clone());
+            map.from_code.insert(code, c);
+        }
+        Ok(map)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Self::from_tsv(&std::fs::read_to_string(path)?)
+            .map_err(|error| Error::msg(format!("{}: {}", path.display(), error)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.to_code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.to_code.is_empty()
+    }
+
+    /// 在 `bytes` 开头查找最长的已映射编码
+    fn lookup(&self, bytes: &[u8]) -> Option<(char, usize)> {
+        (1..=self.max_len.min(bytes.len()))
+            .rev()
+            .find_map(|len| Some((*self.from_code.get(&bytes[..len])?, len)))
+    }
+
+    /// 解码之后应用：把按 `encoding` 解码出的文本中已映射的编码换成对应字符
+    pub fn apply_decoded(&self, encoding: TextEncoding, text: &str) -> String {
+        encoding.decode_mapped(&encoding.encode(text).0, |bytes| self.lookup(bytes))
+    }
+
+    /// 编码之前应用：把已映射的字符换成其编码按 `encoding` 解码的结果，其余文本不变
+    pub fn apply_encoding(&self, encoding: TextEncoding, text: &str) -> String {
+        let mut result = String::with_capacity(text.len());
+        for (unit, byte) in encoding.units(text) {
+            let code = unit.chars().next().and_then(|c| self.to_code.get(&c));
+            match (byte, code) {
+                (None, Some(code)) => result.push_str(&encoding.decode(code)),
+                _ => result.push_str(unit),
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn test_charmap() {
+    let sjis = TextEncoding::default();
+    // 889F 原为「亜」，EB40 在 Shift-JIS 中未使用
+    let map = CharMap::from_tsv("# 汉化字库\n电\t889F\n车\t0xEB40\n\n").unwrap();
+    assert_eq!(map.len(), 2);
+    let encoded = map.apply_encoding(sjis, "电车でＤ");
+    assert_eq!(encoded, "亜\\xEB@でＤ");
+    let bytes = sjis.encode(&encoded).0;
+    assert_eq!(bytes, b"\x88\x9F\xEB\x40\x82\xC5\x82\x63");
+    assert_eq!(map.apply_decoded(sjis, &sjis.decode(&bytes)), "电车でＤ");
+    // 未映射的字符保持原样，由编码时报告
+    assert_eq!(map.apply_encoding(sjis, "电话"), "亜话");
+
+    assert!(CharMap::from_tsv("电\t889F\n车\t889F").is_err());
+    assert!(CharMap::from_tsv("电车\t889F").is_err());
+    let error = CharMap::from_tsv("\n电\tXYZ").unwrap_err().to_string();
+    assert_eq!(error, "Line 2: \"XYZ\" is not a code of 1 to 4 hex bytes");
+}
@@ -0,0 +1,676 @@
+//
+// Densha De D Tools
+// Copyright (C) 2021 SteveXMH
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+//
+
+//! 浏览 Pack 条目的终端界面
+//!
+//! 左侧为条目树，右侧为条目信息与解密后数据的十六进制或文本预览，
+//! 可以解出选中的条目，或以文件替换条目后重建 Pack。
+
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, ListState, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::fvt::{variant::hex_dump, Fvt};
+use crate::kcap::{entry_path, KCAPPackReader, KCAPPackWriter};
+use crate::sniff::{self, FileKind};
+use crate::text::{charmap::CharMap, TextEncoding};
+
+/// 十六进制预览的字节数
+const HEX_LENGTH: usize = 4096;
+/// 脚本文本预览的字节数
+const TEXT_LENGTH: usize = 16384;
+
+const HELP: &str = "↑↓ move  ←→ fold  Tab hex/text  PgUp/PgDn scroll  x extract  r replace  u undo  w write  q quit";
+
+#[derive(Debug)]
+enum TreeKind {
+    /// 子节点，目录排在同名文件之前
+    Dir(BTreeMap<(String, bool), usize>),
+    /// Pack 中的条目序号
+    File(usize),
+}
+
+#[derive(Debug)]
+struct TreeNode {
+    name: String,
+    /// 条目路径中到此节点为止的部分
+    path: PathBuf,
+    depth: usize,
+    kind: TreeKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Preview {
+    Hex,
+    Text,
+}
+
+pub struct App {
+    pack: Arc<KCAPPackReader>,
+    path: PathBuf,
+    /// 条目名以及字幕与脚本文本的编码
+    encoding: TextEncoding,
+    /// 解码字幕与脚本后应用的字符映射表
+    charmap: Option<Arc<CharMap>>,
+    /// 解出条目的目录
+    output: PathBuf,
+    nodes: Vec<TreeNode>,
+    expanded: BTreeSet<usize>,
+    rows: Vec<usize>,
+    selected: usize,
+    preview: Preview,
+    scroll: u16,
+    /// 待写入的替换文件
+    replacements: BTreeMap<usize, PathBuf>,
+    /// 输入替换文件路径时的内容
+    input: Option<String>,
+    status: String,
+    /// 有未写入的替换时再按一次退出键才退出
+    confirm_quit: bool,
+    quit: bool,
+}
+
+impl App {
+    pub fn new(
+        path: &Path,
+        pack: KCAPPackReader,
+        encoding: TextEncoding,
+        charmap: Option<Arc<CharMap>>,
+        output: &Path,
+    ) -> Self {
+        let mut app = Self {
+            pack: Arc::new(pack),
+            path: path.into(),
+            encoding,
+            charmap,
+            output: output.into(),
+            nodes: Vec::new(),
+            expanded: BTreeSet::new(),
+            rows: Vec::new(),
+            selected: 0,
+            preview: Preview::Hex,
+            scroll: 0,
+            replacements: BTreeMap::new(),
+            input: None,
+            status: String::new(),
+            confirm_quit: false,
+            quit: false,
+        };
+        app.build_tree();
+        app.status = format!("{} entries", app.pack.entries.len());
+        app
+    }
+
+    fn build_tree(&mut self) {
+        self.nodes = vec![TreeNode {
+            name: String::new(),
+            path: PathBuf::new(),
+            depth: 0,
+            kind: TreeKind::Dir(BTreeMap::new()),
+        }];
+        for index in 0..self.pack.entries.len() {
+            let path = entry_path(&self.pack.entries[index].name);
+            let names: Vec<String> = path
+                .iter()
+                .map(|name| name.to_string_lossy().into_owned())
+                .collect();
+            let mut dir = 0;
+            for (depth, name) in names.iter().enumerate() {
+                let is_file = depth + 1 == names.len();
+                let next = self.nodes.len();
+                let children = match &mut self.nodes[dir].kind {
+                    TreeKind::Dir(children) => children,
+                    TreeKind::File(_) => unreachable!(),
+                };
+                let key = (name.clone(), is_file);
+                // 同名的文件都显示出来
+                let child = match children.get(&key) {
+                    Some(&child) if !is_file => child,
+                    Some(_) => {
+                        children.insert((format!("{} ({})", name, index), true), next);
+                        next
+                    }
+                    None => {
+                        children.insert(key, next);
+                        next
+                    }
+                };
+                if child == next {
+                    self.nodes.push(TreeNode {
+                        name: name.clone(),
+                        path: path.iter().take(depth + 1).collect(),
+                        depth,
+                        kind: if is_file {
+                            TreeKind::File(index)
+                        } else {
+                            TreeKind::Dir(BTreeMap::new())
+                        },
+                    });
+                }
+                dir = child;
+            }
+        }
+        self.update_rows();
+    }
+
+    fn update_rows(&mut self) {
+        fn visit(app: &App, node: usize, rows: &mut Vec<usize>) {
+            if let TreeKind::Dir(children) = &app.nodes[node].kind {
+                for &child in children.values() {
+                    rows.push(child);
+                    if app.expanded.contains(&child) {
+                        visit(app, child, rows);
+                    }
+                }
+            }
+        }
+        let mut rows = Vec::new();
+        visit(self, 0, &mut rows);
+        self.rows = rows;
+        self.selected = self.selected.min(self.rows.len().saturating_sub(1));
+    }
+
+    fn current(&self) -> Option<usize> {
+        self.rows.get(self.selected).copied()
+    }
+
+    /// 节点下的所有条目
+    fn entries_of(&self, node: usize) -> Vec<usize> {
+        match &self.nodes[node].kind {
+            TreeKind::File(index) => vec![*index],
+            TreeKind::Dir(children) => children
+                .values()
+                .flat_map(|&child| self.entries_of(child))
+                .collect(),
+        }
+    }
+
+    /// 条目开头最多 `len` 字节，已替换的条目读取替换文件
+    fn head(&self, index: usize, len: usize) -> Result<Vec<u8>> {
+        match self.replacements.get(&index) {
+            Some(path) => {
+                let mut data = std::fs::read(path)?;
+                data.truncate(len);
+                Ok(data)
+            }
+            None => self.pack.read_range(index, 0, len),
+        }
+    }
+
+    fn select(&mut self, selected: usize) {
+        self.selected = selected.min(self.rows.len().saturating_sub(1));
+        self.scroll = 0;
+    }
+
+    pub fn on_key(&mut self, code: KeyCode) {
+        if let Some(input) = &mut self.input {
+            match code {
+                KeyCode::Char(c) => input.push(c),
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Enter => {
+                    let path = PathBuf::from(self.input.take().unwrap_or_default());
+                    self.replace(path);
+                }
+                _ => {}
+            }
+            return;
+        }
+        let confirm_quit = std::mem::take(&mut self.confirm_quit);
+        match code {
+            KeyCode::Up | KeyCode::Char('k') => self.select(self.selected.saturating_sub(1)),
+            KeyCode::Down | KeyCode::Char('j') => self.select(self.selected + 1),
+            KeyCode::Home | KeyCode::Char('g') => self.select(0),
+            KeyCode::End | KeyCode::Char('G') => self.select(self.rows.len()),
+            KeyCode::Right | KeyCode::Char('l') | KeyCode::Enter => {
+                if let Some(node) = self.current() {
+                    if let TreeKind::Dir(_) = self.nodes[node].kind {
+                        self.expanded.insert(node);
+                        self.update_rows();
+                    }
+                }
+            }
+            KeyCode::Left | KeyCode::Char('h') => {
+                if let Some(node) = self.current() {
+                    if !self.expanded.remove(&node) {
+                        // 已折叠时跳到上一级目录
+                        let depth = self.nodes[node].depth;
+                        if let Some(parent) = self.rows[..self.selected]
+                            .iter()
+                            .rposition(|&row| self.nodes[row].depth < depth)
+                        {
+                            self.select(parent);
+                        }
+                    }
+                    self.update_rows();
+                }
+            }
+            KeyCode::Tab => {
+                self.preview = match self.preview {
+                    Preview::Hex => Preview::Text,
+                    Preview::Text => Preview::Hex,
+                };
+                self.scroll = 0;
+            }
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_add(10),
+            KeyCode::PageUp => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Char('x') => {
+                self.status = match self.extract() {
+                    Ok(count) => {
+                        format!("Extracted {} entries to {}", count, self.output.display())
+                    }
+                    Err(err) => format!("Can't extract: {}", err),
+                }
+            }
+            KeyCode::Char('r') => {
+                if let Some(TreeKind::File(index)) =
+                    self.current().map(|node| &self.nodes[node].kind)
+                {
+                    let path = self
+                        .output
+                        .join(entry_path(&self.pack.entries[*index].name));
+                    self.input = Some(path.to_string_lossy().into_owned());
+                }
+            }
+            KeyCode::Char('u') => {
+                for index in self
+                    .current()
+                    .map(|node| self.entries_of(node))
+                    .unwrap_or_default()
+                {
+                    self.replacements.remove(&index);
+                }
+                self.status = format!("{} replacements to write", self.replacements.len());
+            }
+            KeyCode::Char('w') => {
+                self.status = match self.write() {
+                    Ok(count) => format!(
+                        "Wrote {} with {} replaced entries",
+                        self.path.display(),
+                        count
+                    ),
+                    Err(err) => format!("Can't write: {}", err),
+                }
+            }
+            KeyCode::Char('q') | KeyCode::Esc => {
+                if self.replacements.is_empty() || confirm_quit {
+                    self.quit = true;
+                } else {
+                    self.confirm_quit = true;
+                    self.status = format!(
+                        "Unwritten replacements of {} entries, press q again to quit or w to write",
+                        self.replacements.len()
+                    );
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// 解出选中的文件或目录下的所有条目，返回条目数
+    fn extract(&self) -> Result<usize> {
+        let entries = self
+            .current()
+            .map(|node| self.entries_of(node))
+            .unwrap_or_default();
+        for &index in &entries {
+            let entry = &self.pack.entries[index];
+            let save_file = self.output.join(entry_path(&entry.name));
+            if let Some(dir) = save_file.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(save_file, self.head(index, entry.size)?)?;
+        }
+        Ok(entries.len())
+    }
+
+    fn replace(&mut self, path: PathBuf) {
+        let index = match self.current().map(|node| &self.nodes[node].kind) {
+            Some(TreeKind::File(index)) => *index,
+            _ => return,
+        };
+        if path.is_file() {
+            self.status = format!(
+                "{} will be replaced by {}, press w to write",
+                self.pack.entries[index].name,
+                path.display()
+            );
+            self.replacements.insert(index, path);
+        } else {
+            self.status = format!("{} is not a file", path.display());
+        }
+    }
+
+    /// 以替换后的条目重建 Pack，返回替换的条目数
+    fn write(&mut self) -> Result<usize> {
+        if self.replacements.is_empty() {
+            return Ok(0);
+        }
+        let mut writer = KCAPPackWriter::with_key_table(Some(self.pack.key_table.clone()));
+        writer.encoding = self.encoding;
+        for (index, entry) in self.pack.entries.iter().enumerate() {
+            match self.replacements.get(&index) {
+                Some(path) => writer.add_entry(path, &entry.name)?,
+                None => writer.add_pack_entry(self.pack.clone(), index, &entry.name),
+            }
+        }
+        writer.write_file(&self.path)?;
+        let count = self.replacements.len();
+        self.replacements.clear();
+        // 写入时条目会重新排序，按路径恢复展开的目录与选中的节点
+        let expanded: BTreeSet<PathBuf> = self
+            .expanded
+            .iter()
+            .map(|&node| self.nodes[node].path.clone())
+            .collect();
+        let selected = self.current().map(|node| self.nodes[node].path.clone());
+        let key_table = self.pack.key_table.clone();
+        self.pack = Arc::new(KCAPPackReader::with_encoding(
+            &self.path,
+            key_table,
+            self.encoding,
+        )?);
+        self.build_tree();
+        self.expanded = (0..self.nodes.len())
+            .filter(|&node| expanded.contains(&self.nodes[node].path))
+            .collect();
+        self.update_rows();
+        if let Some(selected) = selected {
+            if let Some(row) = self
+                .rows
+                .iter()
+                .position(|&row| self.nodes[row].path == selected)
+            {
+                self.selected = row;
+            }
+        }
+        Ok(count)
+    }
+
+    fn info(&self, node: usize) -> Vec<Line<'static>> {
+        match self.nodes[node].kind {
+            TreeKind::File(index) => {
+                let entry = &self.pack.entries[index];
+                let file_type = self
+                    .head(index, sniff::SNIFF_LENGTH)
+                    .map(|head| sniff::sniff(&head).to_string())
+                    .unwrap_or_else(|err| err.to_string());
+                let mut lines = vec![
+                    Line::from(format!("Name:      {}", entry.name)),
+                    Line::from(format!("Index:     {}", index)),
+                    Line::from(format!("Offset:    0x{:08X}", entry.offset)),
+                    Line::from(format!("Size:      {}", entry.size)),
+                    Line::from(format!(
+                        "Encrypted: {}",
+                        if entry.encrypted { "yes" } else { "no" }
+                    )),
+                    Line::from(format!("CRC32:     0x{:08X}", entry.crc32)),
+                    Line::from(format!("Type:      {}", file_type)),
+                ];
+                if let Some(path) = self.replacements.get(&index) {
+                    lines.push(Line::from(format!("Replaced:  {}", path.display())).yellow());
+                }
+                lines
+            }
+            TreeKind::Dir(_) => {
+                let entries = self.entries_of(node);
+                let size: usize = entries.iter().map(|&i| self.pack.entries[i].size).sum();
+                vec![
+                    Line::from(format!("Directory: {}", self.nodes[node].name)),
+                    Line::from(format!("Entries:   {}", entries.len())),
+                    Line::from(format!("Size:      {}", size)),
+                ]
+            }
+        }
+    }
+
+    fn preview_text(&self, index: usize) -> Result<String> {
+        let size = self.pack.entries[index].size;
+        let file_type = sniff::sniff(&self.head(index, sniff::SNIFF_LENGTH)?);
+        Ok(match (self.preview, file_type.kind) {
+            (Preview::Hex, _) => hex_dump(&self.head(index, HEX_LENGTH)?),
+            (Preview::Text, FileKind::Fvt) => {
+                let data = self.head(
+                    index,
+                    self.replacements.get(&index).map_or(size, |_| usize::MAX),
+                )?;
+                let mut fvt = Fvt::from_read_with(&mut &data[..], self.encoding)?;
+                if let Some(charmap) = &self.charmap {
+                    fvt.map_decoded(charmap);
+                }
+                fvt.records
+                    .iter()
+                    .enumerate()
+                    .map(|(i, record)| {
+                        format!(
+                            "{:>4} {:>8} {}\n",
+                            i,
+                            record.u32_unknown0,
+                            record.text.replace('\n', "⏎")
+                        )
+                    })
+                    .collect()
+            }
+            (Preview::Text, FileKind::Script) => {
+                let text = self.encoding.decode(&self.head(index, TEXT_LENGTH)?);
+                match &self.charmap {
+                    Some(charmap) => charmap.apply_decoded(self.encoding, &text),
+                    None => text,
+                }
+            }
+            (Preview::Text, _) => format!("No text preview for {}", file_type),
+        })
+    }
+
+    pub fn render(&self, frame: &mut Frame) {
+        let [main, status] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(2)]).areas(frame.area());
+        let [tree, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+        let [info, preview] =
+            Layout::vertical([Constraint::Length(10), Constraint::Min(3)]).areas(right);
+
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|&node| {
+                let node_ref = &self.nodes[node];
+                let indent = "  ".repeat(node_ref.depth);
+                match node_ref.kind {
+                    TreeKind::Dir(_) => {
+                        let mark = if self.expanded.contains(&node) {
+                            "▾"
+                        } else {
+                            "▸"
+                        };
+                        ListItem::new(format!("{}{} {}", indent, mark, node_ref.name)).bold()
+                    }
+                    TreeKind::File(index) if self.replacements.contains_key(&index) => {
+                        ListItem::new(format!("{}  {} *", indent, node_ref.name)).yellow()
+                    }
+                    TreeKind::File(_) => ListItem::new(format!("{}  {}", indent, node_ref.name)),
+                }
+            })
+            .collect();
+        let title = self
+            .path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let list = List::new(items)
+            .block(Block::bordered().title(title))
+            .highlight_style(Style::new().reversed());
+        let mut state = ListState::default().with_selected(self.current().map(|_| self.selected));
+        frame.render_stateful_widget(list, tree, &mut state);
+
+        let (info_lines, preview_text) = match self.current() {
+            Some(node) => {
+                let preview_text = match self.nodes[node].kind {
+                    TreeKind::File(index) => self
+                        .preview_text(index)
+                        .unwrap_or_else(|err| format!("Can't preview: {}", err)),
+                    TreeKind::Dir(_) => String::new(),
+                };
+                (self.info(node), preview_text)
+            }
+            None => (Vec::new(), String::new()),
+        };
+        frame.render_widget(
+            Paragraph::new(info_lines).block(Block::bordered().title("Entry")),
+            info,
+        );
+        let preview_title = match self.preview {
+            Preview::Hex => "Hex",
+            Preview::Text => "Text",
+        };
+        frame.render_widget(
+            Paragraph::new(preview_text)
+                .block(Block::bordered().title(preview_title))
+                .scroll((self.scroll, 0)),
+            preview,
+        );
+
+        let lines = match &self.input {
+            Some(input) => vec![
+                Line::from("Replace with file (Enter to confirm, Esc to cancel):"),
+                Line::from(format!("> {}", input)),
+            ],
+            None => vec![Line::from(self.status.clone()), Line::from(HELP).dim()],
+        };
+        frame.render_widget(Paragraph::new(lines), status);
+    }
+
+    pub fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.render(frame))?;
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.on_key(key.code);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 在终端中浏览 Pack，`output` 为解出条目的目录
+pub fn run(
+    path: &Path,
+    pack: KCAPPackReader,
+    encoding: TextEncoding,
+    charmap: Option<Arc<CharMap>>,
+    output: &Path,
+) -> Result<()> {
+    let mut app = App::new(path, pack, encoding, charmap, output);
+    let mut terminal = ratatui::init();
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result
+}
+
+#[test]
+fn test_tui() {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    let root = std::env::temp_dir().join(format!("denshaded-tui-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let fvt = b"D3_FVT\x01\0\0\0\x02\0\0\0\x03\0\0\0\x07\x07\x00<b>&\n\x93\x64";
+    let mut writer = KCAPPackWriter::new(Some("PackPass".into()));
+    writer.add_data(fvt.to_vec(), "fvt\\001.FVT");
+    writer.add_data(b"\x89PNG\r\n\x1a\n".to_vec(), "title.png");
+    let path = root.join("tui.Pack");
+    writer.write_file(&path).unwrap();
+    let pack = KCAPPackReader::new(&path, "PackPass").unwrap();
+    let output = root.join("tui");
+    let mut app = App::new(&path, pack, TextEncoding::default(), None, &output);
+
+    let names = |app: &App| -> Vec<String> {
+        app.rows
+            .iter()
+            .map(|&row| app.nodes[row].name.clone())
+            .collect()
+    };
+    assert_eq!(names(&app), ["fvt", "title.png"]);
+    app.on_key(KeyCode::Enter);
+    assert_eq!(names(&app), ["fvt", "001.FVT", "title.png"]);
+
+    // 解出整个目录
+    app.on_key(KeyCode::Char('x'));
+    assert_eq!(
+        std::fs::read(output.join("fvt").join("001.FVT")).unwrap(),
+        fvt
+    );
+
+    app.on_key(KeyCode::Down);
+    app.on_key(KeyCode::Tab);
+    let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+    terminal.draw(|frame| app.render(frame)).unwrap();
+    let screen: String = terminal
+        .backend()
+        .buffer()
+        .content()
+        .iter()
+        .map(|cell| cell.symbol())
+        .collect();
+    assert!(screen.contains("fvt/D3_FVT"));
+    assert!(screen.contains("CRC32:     0x"));
+    assert!(screen.contains("<b>&"));
+
+    // 替换 PNG 后写回 Pack
+    app.on_key(KeyCode::Down);
+    let replacement = root.join("title.png");
+    std::fs::write(&replacement, b"\x89PNG\r\n\x1a\nreplaced").unwrap();
+    app.on_key(KeyCode::Char('r'));
+    app.input = Some(String::new());
+    for c in replacement.to_string_lossy().chars() {
+        app.on_key(KeyCode::Char(c));
+    }
+    app.on_key(KeyCode::Enter);
+    assert_eq!(app.replacements.len(), 1);
+    app.on_key(KeyCode::Char('q'));
+    assert!(!app.quit);
+    app.on_key(KeyCode::Char('w'));
+    assert!(app.replacements.is_empty());
+    assert_eq!(names(&app), ["fvt", "001.FVT", "title.png"]);
+    assert_eq!(names(&app)[app.selected], "title.png");
+    let pack = KCAPPackReader::new(&path, "PackPass").unwrap();
+    let index = pack
+        .entries
+        .iter()
+        .position(|entry| entry.name == "title.png")
+        .unwrap();
+    assert_eq!(
+        pack.read_range(index, 0, 64).unwrap(),
+        b"\x89PNG\r\n\x1a\nreplaced"
+    );
+    app.on_key(KeyCode::Char('q'));
+    assert!(app.quit);
+    std::fs::remove_dir_all(&root).unwrap();
+}